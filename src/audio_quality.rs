@@ -0,0 +1,124 @@
+use std::fmt;
+
+use crate::{speech_detector::frame_level, utterance::Utterance};
+
+/// Samples this close to full scale count as clipped.
+const CLIP_LEVEL: i16 = i16::MAX - 1;
+
+/// Audio quality of one utterance, to tell line problems from recognition problems.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioQuality {
+    /// Share of clipped samples, 0..1.
+    pub clipping: f32,
+    /// RMS level in dBFS.
+    pub level: f32,
+    /// Level above the noise floor, dB.
+    pub snr: f32,
+    pub dropouts: usize,
+    /// Total duration of dropouts, ms.
+    pub dropout_duration: usize,
+}
+
+impl AudioQuality {
+    pub fn measure(utterance: &Utterance, noise_floor: f32) -> Self {
+        let clipped = utterance
+            .samples
+            .iter()
+            .filter(|&&s| s >= CLIP_LEVEL || s <= -CLIP_LEVEL)
+            .count();
+        let level = frame_level(&utterance.samples).max(-100.0);
+        Self {
+            clipping: clipped as f32 / utterance.samples.len().max(1) as f32,
+            level,
+            snr: level - noise_floor,
+            dropouts: utterance.dropouts,
+            dropout_duration: utterance.dropout_duration,
+        }
+    }
+
+    /// Name/value pairs for `Vendor-Specific-Parameters`.
+    pub fn params(&self) -> [(&'static str, String); 5] {
+        [
+            ("audio-clipping", format!("{:.4}", self.clipping)),
+            ("audio-level", format!("{:.1}", self.level)),
+            ("audio-snr", format!("{:.1}", self.snr)),
+            ("audio-dropouts", self.dropouts.to_string()),
+            ("audio-dropout-duration", self.dropout_duration.to_string()),
+        ]
+    }
+}
+
+impl fmt::Display for AudioQuality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "clipping {:.2}%, level {:.1} dBFS, SNR {:.1} dB, {} dropouts of {} ms",
+            self.clipping * 100.0,
+            self.level,
+            self.snr,
+            self.dropouts,
+            self.dropout_duration
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utterance(samples: Vec<i16>) -> Utterance {
+        Utterance {
+            samples,
+            ..Utterance::new(8000)
+        }
+    }
+
+    /// A square wave at about the given level in dBFS.
+    fn square(level: f32, len: usize) -> Vec<i16> {
+        let amplitude = (i16::MAX as f32 * 10f32.powf(level / 20.0)).round() as i16;
+        (0..len)
+            .map(|n| if n % 2 == 0 { amplitude } else { -amplitude })
+            .collect()
+    }
+
+    #[test]
+    fn measures_clipping_share() {
+        let mut samples = square(-20.0, 1000);
+        for sample in samples.iter_mut().take(50) {
+            *sample = if *sample > 0 { i16::MAX } else { i16::MIN };
+        }
+        let quality = AudioQuality::measure(&utterance(samples), -60.0);
+        assert!((quality.clipping - 0.05).abs() < 1e-6);
+        let quality = AudioQuality::measure(&utterance(square(-1.0, 1000)), -60.0);
+        assert_eq!(quality.clipping, 0.0);
+    }
+
+    #[test]
+    fn measures_level_and_snr() {
+        let quality = AudioQuality::measure(&utterance(square(-20.0, 1000)), -50.0);
+        assert!((quality.level + 20.0).abs() < 0.01);
+        assert!((quality.snr - 30.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn silence_has_floor_level() {
+        let quality = AudioQuality::measure(&utterance(vec![0; 1000]), -90.0);
+        assert_eq!(quality.level, -100.0);
+        assert_eq!(quality.clipping, 0.0);
+        let quality = AudioQuality::measure(&utterance(Vec::new()), -90.0);
+        assert_eq!((quality.level, quality.clipping), (-100.0, 0.0));
+    }
+
+    #[test]
+    fn reports_dropouts_in_params() {
+        let mut utterance = utterance(square(-20.0, 1000));
+        utterance.dropouts = 2;
+        utterance.dropout_duration = 40;
+        let params = AudioQuality::measure(&utterance, -60.0).params();
+        assert_eq!(params[0], ("audio-clipping", "0.0000".to_owned()));
+        assert_eq!(params[1], ("audio-level", "-20.0".to_owned()));
+        assert_eq!(params[2], ("audio-snr", "40.0".to_owned()));
+        assert_eq!(params[3], ("audio-dropouts", "2".to_owned()));
+        assert_eq!(params[4], ("audio-dropout-duration", "40".to_owned()));
+    }
+}
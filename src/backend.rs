@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::{config::EngineConfig, utterance::Utterance};
+
+pub use demo::{DemoBackend, DemoStreamingBackend};
+pub use http::{AudioFormat, HintPlacement, HttpBackend};
+pub use streaming::StreamingRecognition;
+pub use subprocess::{SubprocessBackend, Transport};
+pub use vosk::VoskBackend;
+
+mod demo;
+mod http;
+mod streaming;
+mod subprocess;
+mod vosk;
+
+/// What the recognition request asks of the recognizer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecognitionParams {
+    pub language: String,
+    /// Ids of the active grammars.
+    pub grammars: Vec<String>,
+    /// Phrases of the active grammars, as hints for open vocabulary recognizers.
+    pub phrases: Vec<String>,
+    pub n_best: usize,
+    pub confidence_threshold: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Word {
+    pub text: String,
+    /// Offsets from the utterance start, ms.
+    pub start: usize,
+    pub end: usize,
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Alternative {
+    pub text: String,
+    pub confidence: f32,
+    pub words: Vec<Word>,
+    /// Grammar the text matched, filled in by the engine.
+    #[serde(skip)]
+    pub grammar: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RecognitionResult {
+    /// Best first.
+    pub alternatives: Vec<Alternative>,
+}
+
+/// Batch recognizer: gets the whole utterance after endpointing.
+/// Called on a worker thread, so it may block.
+pub trait RecognizerBackend: Send + Sync {
+    fn recognize(
+        &self,
+        utterance: &Utterance,
+        params: &RecognitionParams,
+    ) -> Result<RecognitionResult, String>;
+}
+
+/// Streaming recognizer: gets audio from the speech onset on, while the caller speaks.
+pub trait StreamingBackend: Send + Sync {
+    fn start(
+        &self,
+        params: &RecognitionParams,
+        sample_rate: usize,
+    ) -> Result<Box<dyn StreamingSession>, String>;
+}
+
+/// One utterance of a streaming recognizer, driven from a worker thread, so calls may block.
+pub trait StreamingSession: Send {
+    fn feed(&mut self, samples: &[i16]) -> Result<(), String>;
+    /// Hypothesis updated since the last call, if any.
+    fn partial(&mut self) -> Option<String>;
+    /// End of speech, the final result.
+    fn finish(self: Box<Self>) -> Result<RecognitionResult, String>;
+    fn cancel(self: Box<Self>);
+}
+
+#[derive(Clone)]
+pub enum Backend {
+    Batch(Arc<dyn RecognizerBackend>),
+    Streaming(Arc<dyn StreamingBackend>),
+}
+
+/// Creates the backend named by the `backend` param.
+pub fn create(config: &EngineConfig) -> Result<Backend, String> {
+    log::info!("Creating {:?} recognizer backend", config.backend);
+    match config.backend.as_str() {
+        "demo" => Ok(Backend::Batch(Arc::new(DemoBackend))),
+        "demo-streaming" => Ok(Backend::Streaming(Arc::new(DemoStreamingBackend))),
+        "subprocess" => Ok(Backend::Batch(Arc::new(SubprocessBackend::new(config)?))),
+        "http" => Ok(Backend::Batch(Arc::new(HttpBackend::new(config)?))),
+        "vosk" => Ok(Backend::Streaming(Arc::new(VoskBackend::new(config)))),
+        name => Err(format!("unknown recognizer backend {:?}", name)),
+    }
+}
@@ -0,0 +1,105 @@
+use super::{
+    Alternative, RecognitionParams, RecognitionResult, RecognizerBackend, StreamingBackend,
+    StreamingSession, Word,
+};
+use crate::utterance::Utterance;
+
+const DEMO_RESULT: &str = "Привет, мир!";
+const DEMO_CONFIDENCE: f32 = 0.9;
+/// Audio per word revealed in partial results.
+const DEMO_WORD_DURATION: usize = 500;
+
+/// Answers every utterance with the same phrase, words spread evenly over the utterance.
+#[derive(Debug)]
+pub struct DemoBackend;
+
+impl RecognizerBackend for DemoBackend {
+    fn recognize(
+        &self,
+        utterance: &Utterance,
+        _params: &RecognitionParams,
+    ) -> Result<RecognitionResult, String> {
+        Ok(demo_result(utterance.duration()))
+    }
+}
+
+/// Streams the same phrase word by word as audio comes in.
+#[derive(Debug)]
+pub struct DemoStreamingBackend;
+
+impl StreamingBackend for DemoStreamingBackend {
+    fn start(
+        &self,
+        _params: &RecognitionParams,
+        sample_rate: usize,
+    ) -> Result<Box<dyn StreamingSession>, String> {
+        Ok(Box::new(DemoSession {
+            sample_rate,
+            samples: 0,
+            words: 0,
+        }))
+    }
+}
+
+struct DemoSession {
+    sample_rate: usize,
+    samples: usize,
+    words: usize,
+}
+
+impl DemoSession {
+    fn duration(&self) -> usize {
+        self.samples * 1000 / self.sample_rate.max(1)
+    }
+}
+
+impl StreamingSession for DemoSession {
+    fn feed(&mut self, samples: &[i16]) -> Result<(), String> {
+        self.samples += samples.len();
+        Ok(())
+    }
+
+    fn partial(&mut self) -> Option<String> {
+        let words =
+            (self.duration() / DEMO_WORD_DURATION).min(DEMO_RESULT.split_whitespace().count());
+        if words == self.words {
+            return None;
+        }
+        self.words = words;
+        Some(
+            DEMO_RESULT
+                .split_whitespace()
+                .take(words)
+                .collect::<Vec<_>>()
+                .join(" "),
+        )
+    }
+
+    fn finish(self: Box<Self>) -> Result<RecognitionResult, String> {
+        Ok(demo_result(self.duration()))
+    }
+
+    fn cancel(self: Box<Self>) {}
+}
+
+fn demo_result(duration: usize) -> RecognitionResult {
+    let words: Vec<&str> = DEMO_RESULT.split_whitespace().collect();
+    let step = duration / words.len();
+    RecognitionResult {
+        alternatives: vec![Alternative {
+            text: DEMO_RESULT.to_owned(),
+            confidence: DEMO_CONFIDENCE,
+            words: words
+                .iter()
+                .enumerate()
+                .map(|(i, word)| Word {
+                    text: (*word).to_owned(),
+                    start: i * step,
+                    end: (i + 1) * step,
+                    confidence: DEMO_CONFIDENCE,
+                })
+                .collect(),
+            grammar: None,
+        }],
+    }
+}
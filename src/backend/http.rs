@@ -0,0 +1,416 @@
+use std::{str::FromStr, thread, time::Duration};
+
+use serde_json::Value;
+
+use super::{Alternative, RecognitionParams, RecognitionResult, RecognizerBackend};
+use crate::{config::EngineConfig, utterance::Utterance, wav};
+
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioFormat {
+    Wav,
+    /// Headerless little-endian 16-bit mono samples.
+    Pcm,
+}
+
+impl FromStr for AudioFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wav" => Ok(Self::Wav),
+            "pcm" => Ok(Self::Pcm),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where the language and grammar hints go.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HintPlacement {
+    Query,
+    Headers,
+}
+
+impl FromStr for HintPlacement {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "query" => Ok(Self::Query),
+            "headers" => Ok(Self::Headers),
+            _ => Err(()),
+        }
+    }
+}
+
+/// POSTs the utterance to a REST recognizer and picks the result out of
+/// its JSON answer with JSON pointers.
+pub struct HttpBackend {
+    agent: ureq::Agent,
+    url: String,
+    format: AudioFormat,
+    hints: HintPlacement,
+    retries: usize,
+    token: Option<String>,
+    alternatives_pointer: String,
+    text_pointer: String,
+    confidence_pointer: String,
+}
+
+impl HttpBackend {
+    pub fn new(config: &EngineConfig) -> Result<Self, String> {
+        if config.http_url.is_empty() {
+            return Err("http-url is not set".to_owned());
+        }
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_millis(config.http_timeout as u64))
+            .build();
+        Ok(Self {
+            agent,
+            url: config.http_url.clone(),
+            format: config.http_format,
+            hints: config.http_hints,
+            retries: config.http_retries,
+            token: Some(config.http_token.clone()).filter(|token| !token.is_empty()),
+            alternatives_pointer: config.http_alternatives_pointer.clone(),
+            text_pointer: config.http_text_pointer.clone(),
+            confidence_pointer: config.http_confidence_pointer.clone(),
+        })
+    }
+
+    fn request(&self, utterance: &Utterance, params: &RecognitionParams) -> ureq::Request {
+        let content_type = match self.format {
+            AudioFormat::Wav => "audio/wav".to_owned(),
+            AudioFormat::Pcm => {
+                format!("audio/x-raw; format=S16LE; rate={}", utterance.sample_rate)
+            }
+        };
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", &content_type);
+        if let Some(token) = self.token.as_ref() {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+        let sample_rate = utterance.sample_rate.to_string();
+        match self.hints {
+            HintPlacement::Query => {
+                request = request
+                    .query("language", &params.language)
+                    .query("sample_rate", &sample_rate);
+                for grammar in params.grammars.iter() {
+                    request = request.query("grammar", grammar);
+                }
+                for phrase in params.phrases.iter() {
+                    request = request.query("phrase", phrase);
+                }
+                request
+            }
+            // Header values are ASCII, so the lists are percent-encoded
+            HintPlacement::Headers => {
+                request = request
+                    .set("X-Speech-Language", &params.language)
+                    .set("X-Sample-Rate", &sample_rate);
+                if !params.grammars.is_empty() {
+                    request = request.set("X-Grammars", &encode_list(&params.grammars));
+                }
+                if !params.phrases.is_empty() {
+                    request = request.set("X-Phrases", &encode_list(&params.phrases));
+                }
+                request
+            }
+        }
+    }
+
+    fn post(&self, utterance: &Utterance, params: &RecognitionParams) -> Result<String, String> {
+        let body = match self.format {
+            AudioFormat::Wav => {
+                wav::encode(&utterance.samples, utterance.sample_rate).map_err(|e| e.to_string())?
+            }
+            AudioFormat::Pcm => utterance
+                .samples
+                .iter()
+                .flat_map(|sample| sample.to_le_bytes())
+                .collect(),
+        };
+        let mut attempt = 0;
+        loop {
+            let error = match self.request(utterance, params).send_bytes(&body) {
+                Ok(response) => return response.into_string().map_err(|e| e.to_string()),
+                // Client errors will not go away on retry
+                Err(ureq::Error::Status(status, response)) if status < 500 => {
+                    return Err(format!(
+                        "{} {}",
+                        status,
+                        response.into_string().unwrap_or_default()
+                    ))
+                }
+                Err(e) => e.to_string(),
+            };
+            if attempt >= self.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            log::warn!("Recognition request failed: {}, retry {}", error, attempt);
+            thread::sleep(RETRY_DELAY * attempt as u32);
+        }
+    }
+
+    fn alternative(&self, value: &Value) -> Option<Alternative> {
+        let text = value.pointer(&self.text_pointer)?.as_str()?;
+        let confidence = value
+            .pointer(&self.confidence_pointer)
+            .and_then(Value::as_f64)
+            .unwrap_or(1.0);
+        Some(Alternative {
+            text: text.to_owned(),
+            confidence: confidence as f32,
+            ..Default::default()
+        })
+    }
+}
+
+impl RecognizerBackend for HttpBackend {
+    fn recognize(
+        &self,
+        utterance: &Utterance,
+        params: &RecognitionParams,
+    ) -> Result<RecognitionResult, String> {
+        let body = self.post(utterance, params)?;
+        log::debug!("Recognizer response {}", body);
+        let response: Value = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+        let alternatives = if self.alternatives_pointer.is_empty() {
+            self.alternative(&response).into_iter().collect()
+        } else {
+            response
+                .pointer(&self.alternatives_pointer)
+                .and_then(Value::as_array)
+                .map(|values| {
+                    values
+                        .iter()
+                        .filter_map(|value| self.alternative(value))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        Ok(RecognitionResult { alternatives })
+    }
+}
+
+/// Comma-separated list with everything but unreserved characters percent-encoded.
+fn encode_list(items: &[String]) -> String {
+    let mut encoded = String::new();
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            encoded.push(',');
+        }
+        for byte in item.bytes() {
+            if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+                encoded.push(byte as char);
+            } else {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc::{self, Receiver},
+    };
+
+    use super::*;
+
+    enum Reply {
+        Status(u16, &'static str),
+        /// Holds the connection without answering.
+        Stall,
+    }
+
+    /// Serves one connection per reply, sends back the request heads.
+    fn stub_server(replies: Vec<Reply>) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/recognize", listener.local_addr().unwrap());
+        let (heads, received) = mpsc::channel();
+        thread::spawn(move || {
+            for reply in replies {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let length = head
+                    .lines()
+                    .find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("content-length:")?
+                            .trim()
+                            .parse()
+                            .ok()
+                    })
+                    .unwrap_or(0);
+                reader.read_exact(&mut vec![0; length]).unwrap();
+                heads.send(head).ok();
+                let mut stream = reader.into_inner();
+                match reply {
+                    Reply::Status(status, body) => {
+                        write!(
+                            stream,
+                            "HTTP/1.1 {} Stub\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            status,
+                            body.len(),
+                            body
+                        )
+                        .unwrap();
+                    }
+                    Reply::Stall => thread::sleep(Duration::from_millis(250)),
+                }
+            }
+        });
+        (url, received)
+    }
+
+    fn backend(url: String, config: EngineConfig) -> HttpBackend {
+        HttpBackend::new(&EngineConfig {
+            http_url: url,
+            http_timeout: 200,
+            ..config
+        })
+        .unwrap()
+    }
+
+    fn recognize(
+        backend: &HttpBackend,
+        params: &RecognitionParams,
+    ) -> Result<RecognitionResult, String> {
+        let utterance = Utterance {
+            samples: vec![0; 800],
+            sample_rate: 8000,
+            ..Default::default()
+        };
+        backend.recognize(&utterance, params)
+    }
+
+    #[test]
+    fn encodes_lists_for_headers() {
+        assert_eq!(encode_list(&[]), "");
+        assert_eq!(
+            encode_list(&["session:menu".to_owned(), "да, нет".to_owned()]),
+            "session%3Amenu,%D0%B4%D0%B0%2C%20%D0%BD%D0%B5%D1%82"
+        );
+        assert_eq!(encode_list(&["a-b_c.d~e".to_owned()]), "a-b_c.d~e");
+    }
+
+    #[test]
+    fn maps_alternatives_with_json_pointers() {
+        let (url, _) = stub_server(vec![Reply::Status(
+            200,
+            r#"{"results":[{"transcript":"да","score":0.7},{"transcript":"два"},{"score":0.1}]}"#,
+        )]);
+        let backend = backend(
+            url,
+            EngineConfig {
+                http_alternatives_pointer: "/results".to_owned(),
+                http_text_pointer: "/transcript".to_owned(),
+                http_confidence_pointer: "/score".to_owned(),
+                ..Default::default()
+            },
+        );
+        let result = recognize(&backend, &RecognitionParams::default()).unwrap();
+        let alternatives: Vec<_> = result
+            .alternatives
+            .iter()
+            .map(|alternative| (alternative.text.as_str(), alternative.confidence))
+            .collect();
+        assert_eq!(alternatives, [("да", 0.7), ("два", 1.0)]);
+    }
+
+    #[test]
+    fn maps_single_result_without_alternatives_pointer() {
+        let (url, _) = stub_server(vec![Reply::Status(
+            200,
+            r#"{"text":"да","confidence":0.9}"#,
+        )]);
+        let backend = backend(url, EngineConfig::default());
+        let result = recognize(&backend, &RecognitionParams::default()).unwrap();
+        assert_eq!(result.alternatives[0].text, "да");
+        assert_eq!(result.alternatives[0].confidence, 0.9);
+    }
+
+    #[test]
+    fn sends_only_non_empty_hint_headers() {
+        let (url, heads) = stub_server(vec![
+            Reply::Status(200, r#"{"text":""}"#),
+            Reply::Status(200, r#"{"text":""}"#),
+        ]);
+        let backend = backend(
+            url,
+            EngineConfig {
+                http_hints: HintPlacement::Headers,
+                ..Default::default()
+            },
+        );
+        let mut params = RecognitionParams {
+            language: "ru-RU".to_owned(),
+            ..Default::default()
+        };
+        recognize(&backend, &params).unwrap();
+        let head = heads.recv().unwrap().to_lowercase();
+        assert!(head.contains("x-speech-language: ru-ru"), "{}", head);
+        assert!(!head.contains("x-grammars"), "{}", head);
+        assert!(!head.contains("x-phrases"), "{}", head);
+        params.grammars = vec!["session:menu".to_owned()];
+        recognize(&backend, &params).unwrap();
+        let head = heads.recv().unwrap().to_lowercase();
+        assert!(head.contains("x-grammars: session%3amenu"), "{}", head);
+        assert!(!head.contains("x-phrases"), "{}", head);
+    }
+
+    #[test]
+    fn client_error_is_not_retried() {
+        let (url, heads) = stub_server(vec![
+            Reply::Status(400, "bad audio"),
+            Reply::Status(200, r#"{"text":"да"}"#),
+        ]);
+        let backend = backend(url, EngineConfig::default());
+        let error = recognize(&backend, &RecognitionParams::default()).unwrap_err();
+        assert_eq!(error, "400 bad audio");
+        heads.recv().unwrap();
+        assert!(heads.recv_timeout(Duration::from_millis(300)).is_err());
+    }
+
+    #[test]
+    fn server_error_and_timeout_are_retried() {
+        let (url, heads) = stub_server(vec![
+            Reply::Status(503, "busy"),
+            Reply::Stall,
+            Reply::Status(200, r#"{"text":"да"}"#),
+        ]);
+        let backend = backend(url, EngineConfig::default());
+        let result = recognize(&backend, &RecognitionParams::default()).unwrap();
+        assert_eq!(result.alternatives[0].text, "да");
+        assert_eq!(heads.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let (url, _) = stub_server(vec![
+            Reply::Status(500, "down"),
+            Reply::Status(502, "down"),
+            Reply::Status(503, "down"),
+        ]);
+        let backend = backend(url, EngineConfig::default());
+        assert!(recognize(&backend, &RecognitionParams::default()).is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender, TryRecvError},
+        Arc,
+    },
+    thread,
+};
+
+use super::{RecognitionParams, RecognitionResult, StreamingBackend};
+
+enum Command {
+    Audio(Vec<i16>),
+    Finish,
+}
+
+/// A streaming session running on its own thread, so that feeding audio
+/// from the media thread never waits for the recognizer.
+/// Dropping it before `finish` cancels the session.
+pub struct StreamingRecognition {
+    commands: Sender<Command>,
+    partials: Receiver<String>,
+    result: Receiver<Result<RecognitionResult, String>>,
+    /// Audio buffers the recognizer is done with, refilled by `feed`.
+    spare: Receiver<Vec<i16>>,
+}
+
+impl StreamingRecognition {
+    pub fn start(
+        backend: Arc<dyn StreamingBackend>,
+        params: RecognitionParams,
+        sample_rate: usize,
+    ) -> Result<Self, String> {
+        let (commands, command_receiver) = mpsc::channel();
+        let (partial_sender, partials) = mpsc::channel();
+        let (result_sender, result) = mpsc::channel();
+        let (spare_sender, spare) = mpsc::channel();
+        thread::Builder::new()
+            .name("recognizer".to_owned())
+            .spawn(move || {
+                let outcome = run(
+                    backend.as_ref(),
+                    &params,
+                    sample_rate,
+                    command_receiver,
+                    partial_sender,
+                    spare_sender,
+                );
+                if let Some(outcome) = outcome {
+                    result_sender.send(outcome).ok();
+                }
+            })
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            commands,
+            partials,
+            result,
+            spare,
+        })
+    }
+
+    pub fn feed(&self, samples: &[i16]) {
+        let mut buffer = self.spare.try_recv().unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(samples);
+        self.commands.send(Command::Audio(buffer)).ok();
+    }
+
+    /// Latest hypothesis since the last call.
+    pub fn partial(&self) -> Option<String> {
+        let mut latest = None;
+        loop {
+            match self.partials.try_recv() {
+                Ok(partial) => latest = Some(partial),
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return latest,
+            }
+        }
+    }
+
+    /// The error that ended the session early, a failed start included.
+    /// Before `finish` the only result there can be is an error.
+    pub fn error(&self) -> Option<String> {
+        match self.result.try_recv() {
+            Ok(Err(e)) => Some(e),
+            Ok(Ok(_)) | Err(_) => None,
+        }
+    }
+
+    /// Ends the audio, the final result arrives on the returned receiver.
+    pub fn finish(self) -> Receiver<Result<RecognitionResult, String>> {
+        self.commands.send(Command::Finish).ok();
+        self.result
+    }
+}
+
+/// `None` when the session was cancelled.
+fn run(
+    backend: &dyn StreamingBackend,
+    params: &RecognitionParams,
+    sample_rate: usize,
+    commands: Receiver<Command>,
+    partials: Sender<String>,
+    spare: Sender<Vec<i16>>,
+) -> Option<Result<RecognitionResult, String>> {
+    let mut session = match backend.start(params, sample_rate) {
+        Ok(session) => session,
+        Err(e) => return Some(Err(e)),
+    };
+    while let Ok(command) = commands.recv() {
+        match command {
+            Command::Audio(samples) => {
+                if let Err(e) = session.feed(&samples) {
+                    session.cancel();
+                    return Some(Err(e));
+                }
+                if let Some(partial) = session.partial() {
+                    partials.send(partial).ok();
+                }
+                spare.send(samples).ok();
+            }
+            Command::Finish => return Some(session.finish()),
+        }
+    }
+    log::debug!("Streaming recognition cancelled");
+    session.cancel();
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::backend::DemoStreamingBackend;
+
+    #[test]
+    fn feeds_audio_in_recycled_buffers() {
+        let stream = StreamingRecognition::start(
+            Arc::new(DemoStreamingBackend),
+            RecognitionParams::default(),
+            8000,
+        )
+        .unwrap();
+        let frame = [0i16; 160];
+        for _ in 0..100 {
+            stream.feed(&frame);
+        }
+        let spare = stream.spare.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(spare.capacity() >= frame.len());
+        stream.feed(&frame);
+        let result = stream
+            .finish()
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        // 101 frames of 20 ms
+        assert_eq!(result.alternatives[0].words.last().unwrap().end, 2020);
+    }
+
+    #[test]
+    fn partials_follow_the_audio() {
+        let stream = StreamingRecognition::start(
+            Arc::new(DemoStreamingBackend),
+            RecognitionParams::default(),
+            8000,
+        )
+        .unwrap();
+        stream.feed(&[0i16; 4000]);
+        let mut partial = None;
+        for _ in 0..100 {
+            partial = stream.partial();
+            if partial.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(partial.as_deref(), Some("Привет,"));
+    }
+}
@@ -0,0 +1,585 @@
+//! Recognition by external processes speaking line-delimited JSON.
+//!
+//! For every utterance the engine writes a request line
+//! `{"sample_rate":8000,"samples":N,"language":"ru-RU","grammars":[..],"phrases":[..],"n_best":1}`
+//! followed by `N` little-endian 16-bit mono samples, and reads back one line,
+//! either `{"alternatives":[{"text":"..","confidence":0.9,"words":[..]}]}`
+//! or `{"error":".."}`.
+//!
+//! The request goes to the process stdin and the answer is read from its stdout,
+//! or both go over a Unix socket the process connects to at `$RECOG_SOCKET`.
+//! Writing to a process that went away fails with EPIPE instead of raising SIGPIPE,
+//! without changing the signal disposition of the server.
+
+use std::{
+    io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write},
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    process::{Child, ChildStdin, Command, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use super::{RecognitionParams, RecognitionResult, RecognizerBackend};
+use crate::{config::EngineConfig, utterance::Utterance};
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+static NEXT_SOCKET: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// Requests on stdin, answers on stdout.
+    Stdin,
+    /// Both over a Unix socket at `$RECOG_SOCKET`.
+    Unix,
+}
+
+impl FromStr for Transport {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdin" => Ok(Self::Stdin),
+            "unix" => Ok(Self::Unix),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Settings {
+    command: String,
+    transport: Transport,
+    socket_dir: PathBuf,
+    timeout: Duration,
+}
+
+/// A pool of processes, each recognizing one utterance at a time.
+/// A process that fails a request is killed and started again.
+pub struct SubprocessBackend {
+    settings: Settings,
+    /// Idle slots, `None` for a process yet to be started.
+    idle: Mutex<Vec<Option<Worker>>>,
+    released: Condvar,
+}
+
+impl SubprocessBackend {
+    pub fn new(config: &EngineConfig) -> Result<Self, String> {
+        if config.subprocess_command.is_empty() {
+            return Err("subprocess-command is not set".to_owned());
+        }
+        let settings = Settings {
+            command: config.subprocess_command.clone(),
+            transport: config.subprocess_transport,
+            socket_dir: PathBuf::from(&config.subprocess_socket_dir),
+            timeout: Duration::from_millis(config.subprocess_timeout as u64),
+        };
+        let idle = (0..config.subprocess_pool_size.max(1))
+            .map(|_| Worker::spawn(&settings).map(Some))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|e| format!("unable to start {:?}: {}", settings.command, e))?;
+        log::info!("Started {} recognizer processes", idle.len());
+        Ok(Self {
+            settings,
+            idle: Mutex::new(idle),
+            released: Condvar::new(),
+        })
+    }
+
+    /// Waits up to the request timeout for a free slot.
+    fn acquire(&self) -> Result<Option<Worker>, String> {
+        let deadline = Instant::now() + self.settings.timeout;
+        let mut idle = self.idle.lock().unwrap();
+        loop {
+            if let Some(slot) = idle.pop() {
+                return Ok(slot);
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(format!(
+                    "no recognizer process free in {} ms",
+                    self.settings.timeout.as_millis()
+                ));
+            }
+            idle = self.released.wait_timeout(idle, timeout).unwrap().0;
+        }
+    }
+
+    fn release(&self, worker: Option<Worker>) {
+        self.idle.lock().unwrap().push(worker);
+        self.released.notify_one();
+    }
+
+    fn restart(&self) -> Option<Worker> {
+        match Worker::spawn(&self.settings) {
+            Ok(worker) => Some(worker),
+            Err(e) => {
+                log::error!("Unable to restart {:?}: {}", self.settings.command, e);
+                None
+            }
+        }
+    }
+}
+
+impl RecognizerBackend for SubprocessBackend {
+    fn recognize(
+        &self,
+        utterance: &Utterance,
+        params: &RecognitionParams,
+    ) -> Result<RecognitionResult, String> {
+        let mut worker = match self
+            .acquire()?
+            .and_then(|mut worker| worker.running().then_some(worker))
+        {
+            Some(worker) => worker,
+            None => match self.restart() {
+                Some(worker) => worker,
+                None => {
+                    self.release(None);
+                    return Err("no recognizer process".to_owned());
+                }
+            },
+        };
+        match worker.recognize(utterance, params, self.settings.timeout) {
+            Ok(response) => {
+                self.release(Some(worker));
+                match response.error {
+                    Some(e) => Err(e),
+                    None => Ok(response.result),
+                }
+            }
+            Err(e) => {
+                log::warn!("Recognizer process {} failed: {}", worker.child.id(), e);
+                drop(worker);
+                let worker = self.restart();
+                self.release(worker);
+                Err(format!("recognizer process failed: {}", e))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    #[serde(flatten)]
+    result: RecognitionResult,
+    error: Option<String>,
+}
+
+/// Where requests go, both are non-blocking.
+enum Input {
+    Pipe(ChildStdin),
+    Socket(UnixStream),
+}
+
+impl Input {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Pipe(stdin) => without_sigpipe(|| stdin.write(data)),
+            Self::Socket(stream) => {
+                let written = unsafe {
+                    libc::send(
+                        stream.as_raw_fd(),
+                        data.as_ptr() as _,
+                        data.len(),
+                        libc::MSG_NOSIGNAL,
+                    )
+                };
+                if written < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(written as usize)
+            }
+        }
+    }
+
+    fn fd(&self) -> RawFd {
+        match self {
+            Self::Pipe(stdin) => stdin.as_raw_fd(),
+            Self::Socket(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+struct Worker {
+    child: Child,
+    input: Input,
+    reader: BufReader<Box<dyn Read + Send>>,
+    /// Waits on the non-blocking descriptors are bounded with `poll`.
+    read_fd: RawFd,
+}
+
+impl Worker {
+    fn spawn(settings: &Settings) -> io::Result<Self> {
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(&settings.command);
+        match settings.transport {
+            Transport::Stdin => {
+                let mut child = command
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()?;
+                let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+                    return Err(Error::new(ErrorKind::BrokenPipe, "no stdio pipes"));
+                };
+                set_nonblocking(stdin.as_raw_fd())?;
+                log::debug!("Started recognizer process {}", child.id());
+                Ok(Self {
+                    child,
+                    read_fd: stdout.as_raw_fd(),
+                    input: Input::Pipe(stdin),
+                    reader: BufReader::new(Box::new(stdout)),
+                })
+            }
+            Transport::Unix => {
+                let path = settings.socket_dir.join(format!(
+                    "rs_unimrcp_recog-{}-{}.sock",
+                    std::process::id(),
+                    NEXT_SOCKET.fetch_add(1, Ordering::Relaxed)
+                ));
+                std::fs::remove_file(&path).ok();
+                let listener = UnixListener::bind(&path)?;
+                let child = command
+                    .env("RECOG_SOCKET", &path)
+                    .stdin(Stdio::null())
+                    .spawn();
+                let accepted = child.and_then(|mut child| {
+                    match accept(&listener, &mut child, settings.timeout) {
+                        Ok(stream) => Ok((child, stream)),
+                        Err(e) => {
+                            child.kill().ok();
+                            child.wait().ok();
+                            Err(e)
+                        }
+                    }
+                });
+                std::fs::remove_file(&path).ok();
+                let (child, stream) = accepted?;
+                set_nonblocking(stream.as_raw_fd())?;
+                log::debug!("Started recognizer process {} on {:?}", child.id(), path);
+                Ok(Self {
+                    child,
+                    read_fd: stream.as_raw_fd(),
+                    input: Input::Socket(stream.try_clone()?),
+                    reader: BufReader::new(Box::new(stream)),
+                })
+            }
+        }
+    }
+
+    fn running(&mut self) -> bool {
+        match self.child.try_wait() {
+            Ok(None) => true,
+            Ok(Some(status)) => {
+                log::warn!(
+                    "Recognizer process {} exited with {}",
+                    self.child.id(),
+                    status
+                );
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn recognize(
+        &mut self,
+        utterance: &Utterance,
+        params: &RecognitionParams,
+        timeout: Duration,
+    ) -> io::Result<Response> {
+        let deadline = Instant::now() + timeout;
+        let request = serde_json::json!({
+            "sample_rate": utterance.sample_rate,
+            "samples": utterance.samples.len(),
+            "language": params.language,
+            "grammars": params.grammars,
+            "phrases": params.phrases,
+            "n_best": params.n_best,
+        });
+        let mut message = request.to_string().into_bytes();
+        message.push(b'\n');
+        message.extend(
+            utterance
+                .samples
+                .iter()
+                .flat_map(|sample| sample.to_le_bytes()),
+        );
+        self.write_all(&message, deadline)?;
+        let line = self.read_line(deadline)?;
+        serde_json::from_str(&line).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// A process that stops reading its input must not hold the request past the deadline.
+    fn write_all(&mut self, mut data: &[u8], deadline: Instant) -> io::Result<()> {
+        while !data.is_empty() {
+            if !ready(self.input.fd(), libc::POLLOUT, deadline)? {
+                return Err(Error::new(ErrorKind::TimedOut, "request not taken in time"));
+            }
+            match self.input.write(data) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "process took no data")),
+                Ok(written) => data = &data[written..],
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn read_line(&mut self, deadline: Instant) -> io::Result<String> {
+        let mut line = Vec::new();
+        loop {
+            if self.reader.buffer().is_empty() && !ready(self.read_fd, libc::POLLIN, deadline)? {
+                return Err(Error::new(ErrorKind::TimedOut, "no answer in time"));
+            }
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "process closed its output",
+                ));
+            }
+            match available.iter().position(|&byte| byte == b'\n') {
+                Some(end) => {
+                    line.extend_from_slice(&available[..end]);
+                    self.reader.consume(end + 1);
+                    break;
+                }
+                None => {
+                    let read = available.len();
+                    line.extend_from_slice(available);
+                    self.reader.consume(read);
+                }
+            }
+        }
+        String::from_utf8(line).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+/// Waits for the process to connect, giving up when it exits.
+fn accept(
+    listener: &UnixListener,
+    child: &mut Child,
+    timeout: Duration,
+) -> io::Result<std::os::unix::net::UnixStream> {
+    listener.set_nonblocking(true)?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        if let Some(status) = child.try_wait()? {
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                format!("process exited with {} before connecting", status),
+            ));
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::new(ErrorKind::TimedOut, "process did not connect"));
+        }
+        thread::sleep(ACCEPT_POLL_INTERVAL);
+    }
+}
+
+/// Runs a pipe write with SIGPIPE blocked in this thread, and takes back
+/// the SIGPIPE it raised so it is not delivered once unblocked.
+fn without_sigpipe<T>(write: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    unsafe {
+        let mut sigpipe = std::mem::zeroed();
+        libc::sigemptyset(&mut sigpipe);
+        libc::sigaddset(&mut sigpipe, libc::SIGPIPE);
+        let mut pending = std::mem::zeroed();
+        libc::sigpending(&mut pending);
+        let already_pending = libc::sigismember(&pending, libc::SIGPIPE) == 1;
+        let mut mask = std::mem::zeroed();
+        libc::pthread_sigmask(libc::SIG_BLOCK, &sigpipe, &mut mask);
+        let result = write();
+        if !already_pending && matches!(&result, Err(e) if e.raw_os_error() == Some(libc::EPIPE)) {
+            let no_wait = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            libc::sigtimedwait(&sigpipe, std::ptr::null_mut(), &no_wait);
+        }
+        libc::pthread_sigmask(libc::SIG_SETMASK, &mask, std::ptr::null_mut());
+        result
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Waits until `fd` is ready for `events` or is closed, `false` past the deadline.
+fn ready(fd: RawFd, events: libc::c_short, deadline: Instant) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    loop {
+        let timeout = deadline
+            .saturating_duration_since(Instant::now())
+            .as_millis()
+            .min(libc::c_int::MAX as u128) as libc::c_int;
+        match unsafe { libc::poll(&mut poll_fd, 1, timeout) } {
+            -1 => {
+                let e = Error::last_os_error();
+                if e.kind() != ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            0 => return Ok(false),
+            _ => return Ok(true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Skips the samples of each request and answers with `answer`.
+    fn responder(answer: &str) -> String {
+        format!(
+            r#"while IFS= read -r line; do
+                n=$(echo "$line" | sed 's/.*"samples":\([0-9]*\).*/\1/')
+                head -c $((n * 2)) > /dev/null
+                echo '{}'
+            done"#,
+            answer
+        )
+    }
+
+    fn backend(command: &str, timeout: usize) -> SubprocessBackend {
+        SubprocessBackend::new(&EngineConfig {
+            subprocess_command: command.to_owned(),
+            subprocess_timeout: timeout,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn utterance(samples: usize) -> Utterance {
+        Utterance {
+            samples: vec![0; samples],
+            sample_rate: 8000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_response() {
+        let response: Response = serde_json::from_str(
+            r#"{"alternatives":[{"text":"да","confidence":0.8,
+                "words":[{"text":"да","start":120,"end":480,"confidence":0.8}]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(response.error, None);
+        let alternative = &response.result.alternatives[0];
+        assert_eq!(alternative.text, "да");
+        assert_eq!(alternative.words[0].start, 120);
+        assert_eq!(alternative.words[0].end, 480);
+        let response: Response = serde_json::from_str(r#"{"error":"bad audio"}"#).unwrap();
+        assert_eq!(response.error.as_deref(), Some("bad audio"));
+        assert!(response.result.alternatives.is_empty());
+    }
+
+    #[test]
+    fn recognizes_over_stdin() {
+        let backend = backend(
+            &responder(r#"{"alternatives":[{"text":"да","confidence":0.8}]}"#),
+            5000,
+        );
+        for _ in 0..2 {
+            let result = backend
+                .recognize(&utterance(8000), &RecognitionParams::default())
+                .unwrap();
+            assert_eq!(result.alternatives[0].text, "да");
+        }
+    }
+
+    #[test]
+    fn reports_process_error() {
+        let backend = backend(&responder(r#"{"error":"bad audio"}"#), 5000);
+        let error = backend
+            .recognize(&utterance(800), &RecognitionParams::default())
+            .unwrap_err();
+        assert_eq!(error, "bad audio");
+    }
+
+    #[test]
+    fn write_to_stuck_process_times_out() {
+        let backend = backend("sleep 10", 200);
+        let started = Instant::now();
+        // Far more than a pipe buffer holds
+        let error = backend
+            .recognize(&utterance(1_000_000), &RecognitionParams::default())
+            .unwrap_err();
+        assert!(error.contains("not taken in time"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    thread_local! {
+        static SIGPIPES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    extern "C" fn count_sigpipe(_: libc::c_int) {
+        SIGPIPES.with(|count| count.set(count.get() + 1));
+    }
+
+    #[test]
+    fn write_to_closed_input_raises_no_sigpipe() {
+        let backend = backend("exec 0<&-; sleep 10", 2000);
+        let previous = unsafe {
+            libc::signal(
+                libc::SIGPIPE,
+                count_sigpipe as *const () as libc::sighandler_t,
+            )
+        };
+        let error = backend
+            .recognize(&utterance(1_000_000), &RecognitionParams::default())
+            .unwrap_err();
+        unsafe {
+            libc::signal(libc::SIGPIPE, previous);
+        }
+        assert!(error.contains("Broken pipe"), "{}", error);
+        assert_eq!(SIGPIPES.with(std::cell::Cell::get), 0);
+    }
+
+    #[test]
+    fn acquire_times_out_when_pool_is_busy() {
+        let backend = backend("sleep 10", 100);
+        let busy = backend.acquire().unwrap();
+        assert!(backend.acquire().is_err());
+        backend.release(busy);
+        assert!(backend.acquire().is_ok());
+    }
+}
@@ -0,0 +1,309 @@
+//! Streaming recognition on a Vosk server over its WebSocket protocol:
+//! a config message, binary PCM chunks each answered with a partial or a final
+//! segment, and `{"eof" : 1}` answered with the last final segment.
+
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tungstenite::{http::Uri, Message, WebSocket};
+
+use super::{
+    Alternative, RecognitionParams, RecognitionResult, StreamingBackend, StreamingSession, Word,
+};
+use crate::config::EngineConfig;
+
+/// Audio sent per message, Vosk answers every message.
+const CHUNK_DURATION: usize = 100;
+const DEFAULT_PORT: u16 = 80;
+
+pub struct VoskBackend {
+    url: String,
+    timeout: Duration,
+    phrase_hints: bool,
+}
+
+impl VoskBackend {
+    pub fn new(config: &EngineConfig) -> Self {
+        Self {
+            url: config.vosk_url.clone(),
+            timeout: Duration::from_millis(config.vosk_timeout as u64),
+            phrase_hints: config.vosk_phrase_hints,
+        }
+    }
+
+    fn connect(&self) -> Result<WebSocket<TcpStream>, String> {
+        let uri: Uri = self
+            .url
+            .parse()
+            .map_err(|e| format!("{}: {}", self.url, e))?;
+        let host = uri
+            .host()
+            .ok_or_else(|| format!("no host in {:?}", self.url))?;
+        let address = (host, uri.port_u16().unwrap_or(DEFAULT_PORT))
+            .to_socket_addrs()
+            .map_err(|e| format!("{}: {}", host, e))?
+            .next()
+            .ok_or_else(|| format!("{} has no address", host))?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout)
+            .map_err(|e| format!("{}: {}", self.url, e))?;
+        stream.set_nodelay(true).ok();
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .and_then(|_| stream.set_write_timeout(Some(self.timeout)))
+            .map_err(|e| e.to_string())?;
+        let (socket, _) = tungstenite::client(self.url.as_str(), stream)
+            .map_err(|e| format!("{}: {}", self.url, e))?;
+        Ok(socket)
+    }
+}
+
+impl StreamingBackend for VoskBackend {
+    fn start(
+        &self,
+        params: &RecognitionParams,
+        sample_rate: usize,
+    ) -> Result<Box<dyn StreamingSession>, String> {
+        let mut socket = self.connect()?;
+        let mut config = serde_json::json!({
+            "sample_rate": sample_rate,
+            "words": 1,
+        });
+        // A phrase list limits Vosk to these words, so it is opt-in
+        if self.phrase_hints && !params.phrases.is_empty() {
+            config["phrase_list"] = serde_json::json!(params.phrases);
+        }
+        socket
+            .send(Message::Text(
+                serde_json::json!({ "config": config }).to_string(),
+            ))
+            .map_err(|e| e.to_string())?;
+        log::debug!("Connected to {} at {} Hz", self.url, sample_rate);
+        Ok(Box::new(VoskSession {
+            socket,
+            chunk: Vec::new(),
+            chunk_size: CHUNK_DURATION * sample_rate / 1000 * 2,
+            transcript: Transcript::default(),
+        }))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Reply {
+    partial: Option<String>,
+    text: Option<String>,
+    result: Vec<ReplyWord>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ReplyWord {
+    word: String,
+    /// Seconds from the stream start.
+    start: f64,
+    end: f64,
+    conf: f32,
+}
+
+struct VoskSession {
+    socket: WebSocket<TcpStream>,
+    /// PCM bytes not sent yet.
+    chunk: Vec<u8>,
+    chunk_size: usize,
+    transcript: Transcript,
+}
+
+/// What Vosk has recognized so far in the session.
+#[derive(Debug, Default)]
+struct Transcript {
+    /// Text of the segments Vosk has finalized.
+    segments: Vec<String>,
+    /// Words of the finalized segments, in ms from the session start,
+    /// which is the utterance start as the session gets the whole utterance.
+    words: Vec<Word>,
+    hypothesis: String,
+    updated: bool,
+}
+
+impl VoskSession {
+    fn send_chunk(&mut self) -> Result<(), String> {
+        let chunk = std::mem::take(&mut self.chunk);
+        self.socket
+            .send(Message::Binary(chunk))
+            .map_err(|e| e.to_string())?;
+        let reply = self.reply()?;
+        self.transcript.handle(reply);
+        Ok(())
+    }
+
+    fn reply(&mut self) -> Result<Reply, String> {
+        loop {
+            match self.socket.read().map_err(|e| e.to_string())? {
+                Message::Text(text) => {
+                    return serde_json::from_str(&text).map_err(|e| e.to_string())
+                }
+                Message::Close(frame) => return Err(format!("connection closed: {:?}", frame)),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Transcript {
+    /// Returns whether the reply is a final segment.
+    fn handle(&mut self, reply: Reply) -> bool {
+        let (current, last) = match (reply.text, reply.partial) {
+            (Some(text), _) => {
+                if !text.is_empty() {
+                    self.segments.push(text);
+                }
+                self.words.extend(reply.result.into_iter().map(|word| Word {
+                    text: word.word,
+                    start: (word.start * 1000.0) as usize,
+                    end: (word.end * 1000.0) as usize,
+                    confidence: word.conf,
+                }));
+                (String::new(), true)
+            }
+            (None, Some(partial)) => (partial, false),
+            (None, None) => return false,
+        };
+        let hypothesis = self
+            .segments
+            .iter()
+            .map(String::as_str)
+            .chain(Some(current.as_str()).filter(|text| !text.is_empty()))
+            .collect::<Vec<_>>()
+            .join(" ");
+        if hypothesis != self.hypothesis {
+            self.hypothesis = hypothesis;
+            self.updated = true;
+        }
+        last
+    }
+
+    fn partial(&mut self) -> Option<String> {
+        if !self.updated {
+            return None;
+        }
+        self.updated = false;
+        Some(self.hypothesis.clone())
+    }
+
+    fn result(self) -> RecognitionResult {
+        if self.segments.is_empty() {
+            return RecognitionResult::default();
+        }
+        let confidence = if self.words.is_empty() {
+            1.0
+        } else {
+            self.words.iter().map(|word| word.confidence).sum::<f32>() / self.words.len() as f32
+        };
+        RecognitionResult {
+            alternatives: vec![Alternative {
+                text: self.segments.join(" "),
+                confidence,
+                words: self.words,
+                grammar: None,
+            }],
+        }
+    }
+}
+
+impl StreamingSession for VoskSession {
+    fn feed(&mut self, samples: &[i16]) -> Result<(), String> {
+        self.chunk
+            .extend(samples.iter().flat_map(|sample| sample.to_le_bytes()));
+        if self.chunk.len() >= self.chunk_size {
+            self.send_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn partial(&mut self) -> Option<String> {
+        self.transcript.partial()
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<RecognitionResult, String> {
+        if !self.chunk.is_empty() {
+            self.send_chunk()?;
+        }
+        self.socket
+            .send(Message::Text(r#"{"eof" : 1}"#.to_owned()))
+            .map_err(|e| e.to_string())?;
+        loop {
+            let reply = self.reply()?;
+            if self.transcript.handle(reply) {
+                break;
+            }
+        }
+        self.socket.close(None).ok();
+        Ok(std::mem::take(&mut self.transcript).result())
+    }
+
+    fn cancel(mut self: Box<Self>) {
+        self.socket.close(None).ok();
+        self.socket.flush().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(transcript: &mut Transcript, reply: &str) -> bool {
+        transcript.handle(serde_json::from_str(reply).unwrap())
+    }
+
+    #[test]
+    fn partials_extend_finished_segments() {
+        let mut transcript = Transcript::default();
+        assert!(!handle(&mut transcript, r#"{"partial" : ""}"#));
+        assert_eq!(transcript.partial(), None);
+        assert!(!handle(&mut transcript, r#"{"partial" : "алло"}"#));
+        assert_eq!(transcript.partial().as_deref(), Some("алло"));
+        assert_eq!(transcript.partial(), None);
+        assert!(handle(
+            &mut transcript,
+            r#"{"result" : [{"conf" : 1.0, "end" : 0.81, "start" : 0.3, "word" : "алло"}],
+                "text" : "алло"}"#
+        ));
+        assert!(!handle(&mut transcript, r#"{"partial" : "да"}"#));
+        assert_eq!(transcript.partial().as_deref(), Some("алло да"));
+    }
+
+    #[test]
+    fn result_joins_segments_with_word_times() {
+        let mut transcript = Transcript::default();
+        handle(
+            &mut transcript,
+            r#"{"result" : [{"conf" : 1.0, "end" : 0.81, "start" : 0.3, "word" : "алло"}],
+                "text" : "алло"}"#,
+        );
+        handle(
+            &mut transcript,
+            r#"{"result" : [{"conf" : 0.5, "end" : 2.0, "start" : 1.52, "word" : "да"}],
+                "text" : "да"}"#,
+        );
+        let result = transcript.result();
+        let alternative = &result.alternatives[0];
+        assert_eq!(alternative.text, "алло да");
+        assert_eq!(alternative.confidence, 0.75);
+        let times: Vec<_> = alternative
+            .words
+            .iter()
+            .map(|word| (word.start, word.end))
+            .collect();
+        assert_eq!(times, [(300, 810), (1520, 2000)]);
+    }
+
+    #[test]
+    fn empty_final_segment_is_no_result() {
+        let mut transcript = Transcript::default();
+        assert!(handle(&mut transcript, r#"{"text" : ""}"#));
+        assert!(transcript.result().alternatives.is_empty());
+    }
+}
@@ -0,0 +1,250 @@
+use std::{ffi::CStr, str::FromStr};
+
+use crate::{
+    backend::{AudioFormat, HintPlacement, Transport},
+    resampler::ResampleQuality,
+    speech_detector::DetectorKind,
+    uni,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeoutAction {
+    Recognize,
+    Complete,
+}
+
+impl FromStr for TimeoutAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "recognize" => Ok(Self::Recognize),
+            "complete" => Ok(Self::Complete),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    pub backend: String,
+    pub language: String,
+    pub n_best_list_length: usize,
+    pub confidence_threshold: f32,
+    pub subprocess_command: String,
+    pub subprocess_transport: Transport,
+    pub subprocess_pool_size: usize,
+    pub subprocess_socket_dir: String,
+    pub subprocess_timeout: usize,
+    pub http_url: String,
+    pub http_format: AudioFormat,
+    pub http_hints: HintPlacement,
+    pub http_timeout: usize,
+    pub http_retries: usize,
+    pub http_token: String,
+    pub http_alternatives_pointer: String,
+    pub http_text_pointer: String,
+    pub http_confidence_pointer: String,
+    pub vosk_url: String,
+    pub vosk_timeout: usize,
+    pub vosk_phrase_hints: bool,
+    pub sample_rates: Vec<usize>,
+    pub recognizer_sample_rate: usize,
+    pub resample_quality: ResampleQuality,
+    pub dc_filter: bool,
+    pub highpass_filter: bool,
+    pub highpass_cutoff: f32,
+    pub noise_suppression: bool,
+    pub noise_suppression_factor: f32,
+    pub agc: bool,
+    pub agc_target_level: f32,
+    pub agc_max_gain: f32,
+    pub speech_detector: DetectorKind,
+    pub vad_threshold: f32,
+    pub vad_noise_margin: f32,
+    pub vad_flatness_threshold: f32,
+    pub vad_speech_timeout: usize,
+    pub vad_hangover: usize,
+    pub audio_preroll: usize,
+    pub max_utterance_duration: usize,
+    pub no_input_timeout: usize,
+    pub speech_complete_timeout: usize,
+    pub speech_incomplete_timeout: usize,
+    pub recognition_timeout: usize,
+    pub recognition_timeout_action: TimeoutAction,
+    pub waveform_dir: String,
+    pub waveform_name: String,
+    pub audio_quality_params: bool,
+    pub debug_tap: bool,
+    pub debug_tap_dir: String,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            backend: "demo".to_owned(),
+            language: "ru-RU".to_owned(),
+            n_best_list_length: 1,
+            confidence_threshold: 0.5,
+            subprocess_command: String::new(),
+            subprocess_transport: Transport::Stdin,
+            subprocess_pool_size: 1,
+            subprocess_socket_dir: "/tmp".to_owned(),
+            subprocess_timeout: 10000,
+            http_url: String::new(),
+            http_format: AudioFormat::Wav,
+            http_hints: HintPlacement::Query,
+            http_timeout: 10000,
+            http_retries: 2,
+            http_token: String::new(),
+            http_alternatives_pointer: String::new(),
+            http_text_pointer: "/text".to_owned(),
+            http_confidence_pointer: "/confidence".to_owned(),
+            vosk_url: "ws://127.0.0.1:2700".to_owned(),
+            vosk_timeout: 10000,
+            vosk_phrase_hints: false,
+            sample_rates: vec![8000, 16000],
+            recognizer_sample_rate: 0,
+            resample_quality: ResampleQuality::Fir,
+            dc_filter: true,
+            highpass_filter: false,
+            highpass_cutoff: 100.0,
+            noise_suppression: false,
+            noise_suppression_factor: 2.0,
+            agc: false,
+            agc_target_level: -20.0,
+            agc_max_gain: 24.0,
+            speech_detector: DetectorKind::Energy,
+            vad_threshold: -55.0,
+            vad_noise_margin: 12.0,
+            vad_flatness_threshold: 0.3,
+            vad_speech_timeout: 300,
+            vad_hangover: 300,
+            audio_preroll: 300,
+            max_utterance_duration: 60000,
+            no_input_timeout: 5000,
+            speech_complete_timeout: 800,
+            speech_incomplete_timeout: 1500,
+            recognition_timeout: 10000,
+            recognition_timeout_action: TimeoutAction::Complete,
+            waveform_dir: "/tmp".to_owned(),
+            waveform_name: "{channel}-{request}.wav".to_owned(),
+            audio_quality_params: false,
+            debug_tap: false,
+            debug_tap_dir: "/tmp".to_owned(),
+        }
+    }
+}
+
+impl EngineConfig {
+    pub unsafe fn leaked(engine: *mut uni::mrcp_engine_t) -> *mut Self {
+        let mut config = Self::default();
+        if !(*engine).config.is_null() {
+            for (name, value) in table_entries((*(*engine).config).params) {
+                config.set(&name, &value);
+            }
+        }
+        log::info!("[DEMO_RECOG] Engine config: {:?}", config);
+        Box::into_raw(Box::new(config))
+    }
+
+    pub unsafe fn destroy(this: *mut Self) {
+        drop(Box::from_raw(this));
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        match name {
+            "backend" => self.backend = value.trim().to_owned(),
+            "language" => self.language = value.trim().to_owned(),
+            "n-best-list-length" => parse_into(name, value, &mut self.n_best_list_length),
+            "confidence-threshold" => parse_into(name, value, &mut self.confidence_threshold),
+            "subprocess-command" => self.subprocess_command = value.trim().to_owned(),
+            "subprocess-transport" => parse_into(name, value, &mut self.subprocess_transport),
+            "subprocess-pool-size" => parse_into(name, value, &mut self.subprocess_pool_size),
+            "subprocess-socket-dir" => self.subprocess_socket_dir = value.trim().to_owned(),
+            "subprocess-timeout" => parse_into(name, value, &mut self.subprocess_timeout),
+            "http-url" => self.http_url = value.trim().to_owned(),
+            "http-format" => parse_into(name, value, &mut self.http_format),
+            "http-hints" => parse_into(name, value, &mut self.http_hints),
+            "http-timeout" => parse_into(name, value, &mut self.http_timeout),
+            "http-retries" => parse_into(name, value, &mut self.http_retries),
+            "http-token" => self.http_token = value.trim().to_owned(),
+            "http-alternatives-pointer" => self.http_alternatives_pointer = value.trim().to_owned(),
+            "http-text-pointer" => self.http_text_pointer = value.trim().to_owned(),
+            "http-confidence-pointer" => self.http_confidence_pointer = value.trim().to_owned(),
+            "vosk-url" => self.vosk_url = value.trim().to_owned(),
+            "vosk-timeout" => parse_into(name, value, &mut self.vosk_timeout),
+            "vosk-phrase-hints" => parse_into(name, value, &mut self.vosk_phrase_hints),
+            "sample-rates" => parse_list_into(name, value, &mut self.sample_rates),
+            "recognizer-sample-rate" => parse_into(name, value, &mut self.recognizer_sample_rate),
+            "resample-quality" => parse_into(name, value, &mut self.resample_quality),
+            "dc-filter" => parse_into(name, value, &mut self.dc_filter),
+            "highpass-filter" => parse_into(name, value, &mut self.highpass_filter),
+            "highpass-cutoff" => parse_into(name, value, &mut self.highpass_cutoff),
+            "noise-suppression" => parse_into(name, value, &mut self.noise_suppression),
+            "noise-suppression-factor" => {
+                parse_into(name, value, &mut self.noise_suppression_factor)
+            }
+            "agc" => parse_into(name, value, &mut self.agc),
+            "agc-target-level" => parse_into(name, value, &mut self.agc_target_level),
+            "agc-max-gain" => parse_into(name, value, &mut self.agc_max_gain),
+            "speech-detector" => parse_into(name, value, &mut self.speech_detector),
+            "vad-threshold" => parse_into(name, value, &mut self.vad_threshold),
+            "vad-noise-margin" => parse_into(name, value, &mut self.vad_noise_margin),
+            "vad-flatness-threshold" => parse_into(name, value, &mut self.vad_flatness_threshold),
+            "vad-speech-timeout" => parse_into(name, value, &mut self.vad_speech_timeout),
+            "vad-hangover" => parse_into(name, value, &mut self.vad_hangover),
+            "audio-preroll" => parse_into(name, value, &mut self.audio_preroll),
+            "max-utterance-duration" => parse_into(name, value, &mut self.max_utterance_duration),
+            "no-input-timeout" => parse_into(name, value, &mut self.no_input_timeout),
+            "speech-complete-timeout" => parse_into(name, value, &mut self.speech_complete_timeout),
+            "speech-incomplete-timeout" => {
+                parse_into(name, value, &mut self.speech_incomplete_timeout)
+            }
+            "recognition-timeout" => parse_into(name, value, &mut self.recognition_timeout),
+            "recognition-timeout-action" => {
+                parse_into(name, value, &mut self.recognition_timeout_action)
+            }
+            "waveform-dir" => self.waveform_dir = value.trim().to_owned(),
+            "waveform-name" => self.waveform_name = value.trim().to_owned(),
+            "audio-quality-params" => parse_into(name, value, &mut self.audio_quality_params),
+            "debug-tap" => parse_into(name, value, &mut self.debug_tap),
+            "debug-tap-dir" => self.debug_tap_dir = value.trim().to_owned(),
+            _ => log::warn!("Unknown param {:?} = {:?}", name, value),
+        }
+    }
+}
+
+pub unsafe fn table_entries(table: *const uni::apr_table_t) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    if table.is_null() {
+        return entries;
+    }
+    let header = uni::apr_table_elts(table);
+    let entry = (*header).elts as *mut uni::apr_table_entry_t;
+    for i in 0..(*header).nelts {
+        let entry = entry.offset(i as _);
+        let key = CStr::from_ptr((*entry).key).to_string_lossy();
+        let val = CStr::from_ptr((*entry).val).to_string_lossy();
+        entries.push((key.into_owned(), val.into_owned()));
+    }
+    entries
+}
+
+fn parse_into<T: FromStr>(name: &str, value: &str, target: &mut T) {
+    match value.trim().parse() {
+        Ok(parsed) => *target = parsed,
+        Err(_) => log::warn!("Invalid value {:?} for param {:?}", value, name),
+    }
+}
+
+fn parse_list_into<T: FromStr>(name: &str, value: &str, target: &mut Vec<T>) {
+    match value
+        .split(',')
+        .map(|item| item.trim().parse())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(parsed) => *target = parsed,
+        Err(_) => log::warn!("Invalid value {:?} for param {:?}", value, name),
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use crate::{speech_detector::SpeechDetectorEvent, uni, wav::WavWriter};
+
+enum Record {
+    Audio(Vec<i16>),
+    Silence(usize),
+    Line(String),
+}
+
+/// Records what a channel receives while recognizing, before resampling and VAD,
+/// with a sidecar of detector events at sample offsets into the recording.
+/// Frames without audio are recorded as silence so the recording keeps wall-clock time.
+/// It is fed only from the media thread, and the files are written on a thread of their own.
+pub struct DebugTap {
+    records: Sender<Record>,
+    /// Audio buffers the writer is done with.
+    spare: Receiver<Vec<i16>>,
+    sample_rate: usize,
+    position: usize,
+    frame_samples: usize,
+}
+
+impl DebugTap {
+    pub fn create(dir: &str, name: &str, sample_rate: usize) -> std::io::Result<Self> {
+        let dir = Path::new(dir);
+        let audio_path = dir.join(format!("{}.wav", name));
+        let events_path = dir.join(format!("{}.events", name));
+        let (records, record_receiver) = mpsc::channel();
+        let (spare_sender, spare) = mpsc::channel();
+        thread::Builder::new()
+            .name("debug-tap".to_owned())
+            .spawn(move || {
+                let written = run(
+                    &audio_path,
+                    &events_path,
+                    sample_rate,
+                    record_receiver,
+                    spare_sender,
+                );
+                if let Err(e) = written {
+                    log::warn!("Debug tap {:?} failed: {}", audio_path, e);
+                }
+            })?;
+        log::info!("Debug tap {:?} at {} Hz", dir.join(name), sample_rate);
+        Ok(Self {
+            records,
+            spare,
+            sample_rate,
+            position: 0,
+            frame_samples: sample_rate * uni::CODEC_FRAME_TIME_BASE as usize / 1000,
+        })
+    }
+
+    pub fn audio(&mut self, samples: &[i16]) {
+        let mut buffer = self.spare.try_recv().unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(samples);
+        self.records.send(Record::Audio(buffer)).ok();
+        self.position += samples.len();
+        self.frame_samples = samples.len();
+    }
+
+    /// A frame time of silence for a frame without audio.
+    pub fn silence(&mut self) {
+        self.records.send(Record::Silence(self.frame_samples)).ok();
+        self.position += self.frame_samples;
+    }
+
+    pub fn note(&mut self, note: &str) {
+        self.line(format_args!("{}", note));
+    }
+
+    pub fn detector_event(&mut self, event: SpeechDetectorEvent) {
+        if event != SpeechDetectorEvent::None {
+            self.line(format_args!("{:?}", event));
+        }
+    }
+
+    fn line(&mut self, text: std::fmt::Arguments) {
+        let line = format!(
+            "{}\t{}\t{}",
+            self.position,
+            self.position * 1000 / self.sample_rate.max(1),
+            text
+        );
+        self.records.send(Record::Line(line)).ok();
+    }
+}
+
+/// Writes records until the tap is dropped.
+fn run(
+    audio_path: &Path,
+    events_path: &Path,
+    sample_rate: usize,
+    records: Receiver<Record>,
+    spare: Sender<Vec<i16>>,
+) -> std::io::Result<()> {
+    let mut audio = WavWriter::create(audio_path, sample_rate)?;
+    let mut events = BufWriter::new(File::create(events_path)?);
+    writeln!(events, "# sample\tms\tevent")?;
+    let mut silence = Vec::new();
+    for record in records {
+        match record {
+            Record::Audio(samples) => {
+                audio.write_samples(&samples)?;
+                spare.send(samples).ok();
+            }
+            Record::Silence(samples) => {
+                silence.resize(samples, 0);
+                audio.write_samples(&silence)?;
+            }
+            Record::Line(line) => writeln!(events, "{}", line)?,
+        }
+    }
+    audio.finish()?;
+    events.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::wav;
+
+    #[test]
+    fn records_silence_for_frames_without_audio() {
+        let dir = std::env::temp_dir().join(format!("debug-tap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut tap = DebugTap::create(dir.to_str().unwrap(), "channel", 8000).unwrap();
+        tap.audio(&[100; 160]);
+        tap.silence();
+        tap.detector_event(SpeechDetectorEvent::Activity);
+        tap.audio(&[200; 160]);
+        drop(tap);
+
+        let path = dir.join("channel.events");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let events = loop {
+            let events = std::fs::read_to_string(&path).unwrap_or_default();
+            if events.lines().count() == 2 || Instant::now() > deadline {
+                break events;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(events.lines().nth(1), Some("320\t40\tActivity"));
+        let (samples, sample_rate) = wav::read(&dir.join("channel.wav")).unwrap();
+        assert_eq!(sample_rate, 8000);
+        assert_eq!(samples.len(), 480);
+        assert!(samples[160..320].iter().all(|&sample| sample == 0));
+        assert_eq!(samples[320], 200);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{config::EngineConfig, speech_detector::frame_level};
+
+pub use noise_suppressor::NoiseSuppressor;
+
+mod noise_suppressor;
+
+/// One step of the pre-processing chain, works in place on a frame.
+pub trait Stage {
+    fn process(&mut self, samples: &mut [i16]);
+}
+
+/// Pre-processing applied to every frame before detection and recognition.
+pub struct DspChain {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl DspChain {
+    pub fn new(config: &EngineConfig, sample_rate: usize) -> Self {
+        let mut stages: Vec<Box<dyn Stage>> = Vec::new();
+        if config.dc_filter {
+            stages.push(Box::new(DcBlocker::new(sample_rate)));
+        }
+        if config.highpass_filter {
+            stages.push(Box::new(Biquad::highpass(
+                config.highpass_cutoff,
+                sample_rate,
+            )));
+        }
+        if config.noise_suppression {
+            stages.push(Box::new(NoiseSuppressor::new(
+                config.noise_suppression_factor,
+                sample_rate,
+            )));
+        }
+        if config.agc {
+            stages.push(Box::new(Agc::new(
+                config.agc_target_level,
+                config.agc_max_gain,
+            )));
+        }
+        log::debug!("DSP chain of {} stages", stages.len());
+        Self { stages }
+    }
+
+    pub fn process(&mut self, samples: &mut [i16]) {
+        for stage in self.stages.iter_mut() {
+            stage.process(samples);
+        }
+    }
+}
+
+fn saturate(sample: f32) -> i16 {
+    sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+const DC_CUTOFF: f32 = 10.0;
+
+/// First order DC blocker: `y[n] = x[n] - x[n-1] + r * y[n-1]`.
+pub struct DcBlocker {
+    r: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl DcBlocker {
+    pub fn new(sample_rate: usize) -> Self {
+        Self {
+            r: 1.0 - 2.0 * std::f32::consts::PI * DC_CUTOFF / sample_rate as f32,
+            x1: 0.0,
+            y1: 0.0,
+        }
+    }
+}
+
+impl Stage for DcBlocker {
+    fn process(&mut self, samples: &mut [i16]) {
+        for sample in samples.iter_mut() {
+            let x = *sample as f32;
+            let y = x - self.x1 + self.r * self.y1;
+            self.x1 = x;
+            self.y1 = y;
+            *sample = saturate(y);
+        }
+    }
+}
+
+/// Second order IIR section, direct form I.
+pub struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Biquad {
+    /// Butterworth high-pass from the RBJ audio EQ cookbook.
+    pub fn highpass(cutoff: f32, sample_rate: usize) -> Self {
+        let omega = 2.0 * std::f32::consts::PI * cutoff / sample_rate as f32;
+        let alpha = omega.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let cos = omega.cos();
+        let a0 = 1.0 + alpha;
+        Self {
+            b: [
+                (1.0 + cos) / 2.0 / a0,
+                -(1.0 + cos) / a0,
+                (1.0 + cos) / 2.0 / a0,
+            ],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+}
+
+impl Stage for Biquad {
+    fn process(&mut self, samples: &mut [i16]) {
+        for sample in samples.iter_mut() {
+            let x = *sample as f32;
+            let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+                - self.a[0] * self.y[0]
+                - self.a[1] * self.y[1];
+            self.x = [x, self.x[0]];
+            self.y = [y, self.y[0]];
+            *sample = saturate(y);
+        }
+    }
+}
+
+const AGC_GATE: f32 = -50.0;
+const AGC_ATTACK: f32 = 0.5;
+const AGC_RELEASE: f32 = 0.02;
+
+/// Frame based automatic gain control towards a target level in dBFS.
+/// Frames below the gate keep the current gain so line noise is not pumped up.
+pub struct Agc {
+    target_level: f32,
+    max_gain: f32,
+    gain: f32,
+}
+
+impl Agc {
+    pub fn new(target_level: f32, max_gain: f32) -> Self {
+        Self {
+            target_level,
+            max_gain,
+            gain: 0.0,
+        }
+    }
+}
+
+impl Stage for Agc {
+    fn process(&mut self, samples: &mut [i16]) {
+        let level = frame_level(samples);
+        if level > AGC_GATE {
+            let wanted = (self.target_level - level).clamp(-self.max_gain, self.max_gain);
+            let rate = if wanted < self.gain {
+                AGC_ATTACK
+            } else {
+                AGC_RELEASE
+            };
+            self.gain += (wanted - self.gain) * rate;
+        }
+        let factor = 10f32.powf(self.gain / 20.0);
+        for sample in samples.iter_mut() {
+            *sample = saturate(*sample as f32 * factor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: usize = 8000;
+    const FRAME: usize = RATE / 50;
+
+    fn tone(frequency: f32, amplitude: f32, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|n| {
+                let phase = 2.0 * std::f32::consts::PI * frequency * n as f32 / RATE as f32;
+                (amplitude * phase.sin()) as i16
+            })
+            .collect()
+    }
+
+    fn process(stage: &mut dyn Stage, samples: &[i16]) -> Vec<i16> {
+        let mut output = samples.to_vec();
+        for frame in output.chunks_mut(FRAME) {
+            stage.process(frame);
+        }
+        output
+    }
+
+    /// Output level over input level in dB after the stage has settled.
+    fn gain(stage: &mut dyn Stage, input: &[i16]) -> f32 {
+        let output = process(stage, input);
+        let settled = input.len() / 2;
+        frame_level(&output[settled..]) - frame_level(&input[settled..])
+    }
+
+    #[test]
+    fn dc_blocker_removes_offset() {
+        let input: Vec<i16> = tone(440.0, 3000.0, RATE * 2)
+            .into_iter()
+            .map(|sample| sample + 2000)
+            .collect();
+        let output = process(&mut DcBlocker::new(RATE), &input);
+        let settled = &output[RATE..];
+        let mean = settled.iter().map(|&sample| sample as f32).sum::<f32>() / settled.len() as f32;
+        assert!(mean.abs() < 20.0, "mean {}", mean);
+        assert!((frame_level(settled) - frame_level(&tone(440.0, 3000.0, RATE))).abs() < 0.5);
+    }
+
+    #[test]
+    fn highpass_attenuates_below_cutoff() {
+        let hum = gain(
+            &mut Biquad::highpass(200.0, RATE),
+            &tone(50.0, 8000.0, RATE),
+        );
+        assert!(hum < -18.0, "50 Hz at {} dB", hum);
+        let voice = gain(
+            &mut Biquad::highpass(200.0, RATE),
+            &tone(1000.0, 8000.0, RATE),
+        );
+        assert!(voice.abs() < 0.5, "1 kHz at {} dB", voice);
+    }
+
+    #[test]
+    fn agc_converges_to_target_level() {
+        let input = tone(440.0, 1000.0, RATE * 10);
+        let mut agc = Agc::new(-20.0, 20.0);
+        let output = process(&mut agc, &input);
+        let level = frame_level(&output[output.len() - FRAME..]);
+        assert!((level + 20.0).abs() < 0.5, "level {}", level);
+    }
+
+    #[test]
+    fn agc_gain_is_clamped() {
+        let quiet = tone(440.0, 300.0, RATE * 10);
+        let boost = gain(&mut Agc::new(-20.0, 6.0), &quiet);
+        assert!((boost - 6.0).abs() < 0.2, "boost {} dB", boost);
+        let loud = tone(440.0, 20000.0, RATE * 10);
+        let cut = gain(&mut Agc::new(-20.0, 6.0), &loud);
+        assert!((cut + 6.0).abs() < 0.2, "cut {} dB", cut);
+    }
+
+    #[test]
+    fn agc_keeps_gain_below_gate() {
+        let input = tone(440.0, 30.0, RATE);
+        let output = process(&mut Agc::new(-20.0, 20.0), &input);
+        assert_eq!(output, input);
+    }
+}
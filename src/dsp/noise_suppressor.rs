@@ -0,0 +1,217 @@
+use std::collections::VecDeque;
+
+use super::{saturate, Stage};
+use crate::{fft, speech_detector::frame_level};
+
+const BLOCK_DURATION: usize = 32;
+/// Blocks within this many dB of the noise floor are taken for noise.
+const NOISE_GATE: f32 = 6.0;
+const NOISE_SMOOTHING: f32 = 0.9;
+/// The noise floor is the quietest block of this window, so it follows
+/// a louder line within the window while pauses between words keep it down in speech.
+const NOISE_FLOOR_WINDOW: usize = 1500;
+const SPECTRAL_FLOOR: f32 = 0.01;
+/// Level taken for digital silence, dBFS.
+const SILENCE_LEVEL: f32 = -90.0;
+
+/// Spectral subtraction over a 50% overlapped STFT with square-root Hann windows.
+/// The noise spectrum is learnt from blocks close to the noise floor.
+/// Delays the signal by one block.
+pub struct NoiseSuppressor {
+    size: usize,
+    hop: usize,
+    factor: f32,
+    window: Vec<f32>,
+    block: Vec<f32>,
+    filled: usize,
+    overlap: Vec<f32>,
+    output: VecDeque<f32>,
+    re: Vec<f32>,
+    im: Vec<f32>,
+    block_samples: Vec<i16>,
+    noise: Vec<f32>,
+    noise_learnt: bool,
+    /// Levels of the latest blocks, dBFS.
+    levels: VecDeque<f32>,
+    window_blocks: usize,
+}
+
+impl NoiseSuppressor {
+    pub fn new(factor: f32, sample_rate: usize) -> Self {
+        let size = (BLOCK_DURATION * sample_rate / 1000).next_power_of_two();
+        let hop = size / 2;
+        let window = (0..size)
+            .map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * i as f32 / size as f32;
+                (0.5 - 0.5 * phase.cos()).sqrt()
+            })
+            .collect();
+        let mut output = VecDeque::with_capacity(size * 2);
+        output.resize(hop, 0.0);
+        Self {
+            size,
+            hop,
+            factor,
+            window,
+            block: vec![0.0; size],
+            filled: size - hop,
+            overlap: vec![0.0; size],
+            output,
+            re: vec![0.0; size],
+            im: vec![0.0; size],
+            block_samples: Vec::with_capacity(size),
+            noise: vec![0.0; size / 2 + 1],
+            noise_learnt: false,
+            levels: VecDeque::new(),
+            window_blocks: (NOISE_FLOOR_WINDOW * sample_rate / 1000 / hop).max(1),
+        }
+    }
+
+    fn noise_floor(&self) -> f32 {
+        self.levels.iter().copied().fold(f32::INFINITY, f32::min)
+    }
+
+    fn process_block(&mut self) {
+        self.block_samples.clear();
+        self.block_samples
+            .extend(self.block[self.size - self.hop..].iter().map(|&s| s as i16));
+        let level = frame_level(&self.block_samples).max(SILENCE_LEVEL);
+        if self.levels.len() == self.window_blocks {
+            self.levels.pop_front();
+        }
+        self.levels.push_back(level);
+        let learn = level <= self.noise_floor() + NOISE_GATE;
+
+        for i in 0..self.size {
+            self.re[i] = self.block[i] * self.window[i];
+            self.im[i] = 0.0;
+        }
+        fft::fft(&mut self.re, &mut self.im);
+
+        let bins = self.size / 2;
+        for k in 0..=bins {
+            let power = self.re[k] * self.re[k] + self.im[k] * self.im[k];
+            if learn {
+                self.noise[k] = if !self.noise_learnt {
+                    power
+                } else {
+                    self.noise[k] * NOISE_SMOOTHING + power * (1.0 - NOISE_SMOOTHING)
+                };
+            }
+            let gain = if power > 0.0 {
+                (1.0 - self.factor * self.noise[k] / power)
+                    .max(SPECTRAL_FLOOR)
+                    .sqrt()
+            } else {
+                0.0
+            };
+            self.re[k] *= gain;
+            self.im[k] *= gain;
+            // Keep the spectrum conjugate symmetric
+            if k > 0 && k < bins {
+                self.re[self.size - k] = self.re[k];
+                self.im[self.size - k] = -self.im[k];
+            }
+        }
+        self.noise_learnt |= learn;
+        fft::ifft(&mut self.re, &mut self.im);
+
+        for i in 0..self.size {
+            self.overlap[i] += self.re[i] * self.window[i];
+        }
+        self.output.extend(self.overlap[..self.hop].iter());
+        self.overlap.copy_within(self.hop.., 0);
+        self.overlap[self.size - self.hop..].fill(0.0);
+        self.block.copy_within(self.hop.., 0);
+        self.filled = self.size - self.hop;
+    }
+}
+
+impl Stage for NoiseSuppressor {
+    fn process(&mut self, samples: &mut [i16]) {
+        for &sample in samples.iter() {
+            self.block[self.filled] = sample as f32;
+            self.filled += 1;
+            if self.filled == self.size {
+                self.process_block();
+            }
+        }
+        for sample in samples.iter_mut() {
+            *sample = saturate(self.output.pop_front().unwrap_or_default());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: usize = 8000;
+
+    /// Deterministic white noise.
+    fn noise(seed: &mut u32, amplitude: f32, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|_| {
+                *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                ((*seed >> 16) as f32 / 32768.0 - 1.0) * amplitude
+            })
+            .map(|sample| sample as i16)
+            .collect()
+    }
+
+    fn process(suppressor: &mut NoiseSuppressor, samples: &[i16]) -> Vec<i16> {
+        let mut output = samples.to_vec();
+        for frame in output.chunks_mut(RATE / 50) {
+            suppressor.process(frame);
+        }
+        output
+    }
+
+    fn attenuation(input: &[i16], output: &[i16]) -> f32 {
+        frame_level(input) - frame_level(output)
+    }
+
+    #[test]
+    fn passes_signal_unchanged_without_subtraction() {
+        let mut seed = 1;
+        let input = noise(&mut seed, 3000.0, RATE);
+        let mut suppressor = NoiseSuppressor::new(0.0, RATE);
+        let output = process(&mut suppressor, &input);
+        let delay = suppressor.size;
+        for (a, b) in input[..RATE - delay].iter().zip(&output[delay..]) {
+            assert!((*a as i32 - *b as i32).abs() <= 2, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn suppresses_steady_noise() {
+        let mut seed = 1;
+        let input = noise(&mut seed, 1000.0, 3 * RATE);
+        let mut suppressor = NoiseSuppressor::new(2.0, RATE);
+        let output = process(&mut suppressor, &input);
+        assert!(attenuation(&input[2 * RATE..], &output[2 * RATE..]) > 6.0);
+    }
+
+    #[test]
+    fn follows_noise_level_change() {
+        let mut seed = 1;
+        let mut input = noise(&mut seed, 500.0, 3 * RATE);
+        // 12 dB louder line from the middle of the call on
+        input.extend(noise(&mut seed, 2000.0, 4 * RATE));
+        let mut suppressor = NoiseSuppressor::new(2.0, RATE);
+        let output = process(&mut suppressor, &input);
+        let tail = input.len() - RATE;
+        assert!(attenuation(&input[tail..], &output[tail..]) > 6.0);
+    }
+
+    #[test]
+    fn follows_noise_level_drop() {
+        let mut seed = 1;
+        let mut input = noise(&mut seed, 2000.0, 3 * RATE);
+        input.extend(noise(&mut seed, 500.0, 3 * RATE));
+        let mut suppressor = NoiseSuppressor::new(2.0, RATE);
+        let output = process(&mut suppressor, &input);
+        let tail = input.len() - RATE;
+        assert!(attenuation(&input[tail..], &output[tail..]) > 6.0);
+    }
+}
@@ -0,0 +1,124 @@
+/// In-place radix-2 FFT over separate real and imaginary parts,
+/// the length must be a power of two.
+pub fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+    debug_assert!(n.is_power_of_two() && im.len() == n);
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * std::f32::consts::PI / len as f32;
+        let (w_im, w_re) = angle.sin_cos();
+        for start in (0..n).step_by(len) {
+            let (mut cur_re, mut cur_im) = (1.0f32, 0.0f32);
+            for k in 0..len / 2 {
+                let a = start + k;
+                let b = a + len / 2;
+                let t_re = re[b] * cur_re - im[b] * cur_im;
+                let t_im = re[b] * cur_im + im[b] * cur_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+                let next_re = cur_re * w_re - cur_im * w_im;
+                cur_im = cur_re * w_im + cur_im * w_re;
+                cur_re = next_re;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+/// In-place inverse of [`fft`], scaled by `1 / n`.
+pub fn ifft(re: &mut [f32], im: &mut [f32]) {
+    im.iter_mut().for_each(|x| *x = -*x);
+    fft(re, im);
+    let scale = 1.0 / re.len() as f32;
+    re.iter_mut().for_each(|x| *x *= scale);
+    im.iter_mut().for_each(|x| *x *= -scale);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-3, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn impulse_has_flat_spectrum() {
+        let mut re = [0.0; 8];
+        let mut im = [0.0; 8];
+        re[0] = 1.0;
+        fft(&mut re, &mut im);
+        assert_close(&re, &[1.0; 8]);
+        assert_close(&im, &[0.0; 8]);
+    }
+
+    #[test]
+    fn matches_naive_dft() {
+        let n = 16;
+        let input: Vec<f32> = (0..n).map(|i| ((i * 7) % 5) as f32 - 2.0).collect();
+        let mut re = input.clone();
+        let mut im = vec![0.0; n];
+        fft(&mut re, &mut im);
+        for k in 0..n {
+            let (mut dft_re, mut dft_im) = (0.0f32, 0.0f32);
+            for (t, x) in input.iter().enumerate() {
+                let angle = -2.0 * std::f32::consts::PI * (k * t) as f32 / n as f32;
+                dft_re += x * angle.cos();
+                dft_im += x * angle.sin();
+            }
+            assert_close(&[re[k], im[k]], &[dft_re, dft_im]);
+        }
+    }
+
+    #[test]
+    fn cosine_lands_in_its_bins() {
+        let n = 32;
+        let mut re: Vec<f32> = (0..n)
+            .map(|t| (2.0 * std::f32::consts::PI * 3.0 * t as f32 / n as f32).cos())
+            .collect();
+        let mut im = vec![0.0; n];
+        fft(&mut re, &mut im);
+        for k in 0..n {
+            let magnitude = re[k].hypot(im[k]);
+            let expected = if k == 3 || k == n - 3 {
+                n as f32 / 2.0
+            } else {
+                0.0
+            };
+            assert!(
+                (magnitude - expected).abs() < 1e-3,
+                "bin {}: {}",
+                k,
+                magnitude
+            );
+        }
+    }
+
+    #[test]
+    fn ifft_inverts_fft() {
+        let input_re: Vec<f32> = (0..64).map(|i| (i as f32 * 0.37).sin()).collect();
+        let input_im: Vec<f32> = (0..64).map(|i| (i as f32 * 0.11).cos()).collect();
+        let (mut re, mut im) = (input_re.clone(), input_im.clone());
+        fft(&mut re, &mut im);
+        ifft(&mut re, &mut im);
+        assert_close(&re, &input_re);
+        assert_close(&im, &input_im);
+    }
+}
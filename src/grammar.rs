@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+
+use srgs::Srgs;
+
+mod srgs;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum GrammarMatch {
+    None,
+    Incomplete,
+    Complete,
+}
+
+#[derive(Debug, Clone)]
+enum GrammarKind {
+    Phrases(Vec<Vec<String>>),
+    Srgs(Srgs),
+    Digits,
+    Open,
+}
+
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    /// The `session:` or `builtin:` URI the client refers to the grammar by.
+    id: String,
+    kind: GrammarKind,
+}
+
+impl Grammar {
+    pub fn parse(id: &str, content_type: &str, body: &str) -> Self {
+        let kind = if content_type.contains("srgs+xml") || content_type.contains("/xml") {
+            match Srgs::parse(body) {
+                Some(srgs) => GrammarKind::Srgs(srgs),
+                None => {
+                    log::warn!("No rules found in grammar {:?}", id);
+                    GrammarKind::Open
+                }
+            }
+        } else {
+            log::debug!(
+                "Grammar {:?} of type {:?} accepts any input",
+                id,
+                content_type
+            );
+            GrammarKind::Open
+        };
+        Self {
+            id: id.to_owned(),
+            kind,
+        }
+    }
+
+    pub fn builtin(uri: &str) -> Self {
+        let name = uri.split('?').next().unwrap_or_default();
+        let kind = if name.ends_with("/digits") {
+            GrammarKind::Digits
+        } else if name.ends_with("/boolean") {
+            GrammarKind::Phrases(
+                ["yes", "no", "да", "нет"]
+                    .iter()
+                    .map(|word| vec![(*word).to_owned()])
+                    .collect(),
+            )
+        } else {
+            GrammarKind::Open
+        };
+        Self {
+            id: uri.to_owned(),
+            kind,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn phrases(&self) -> Vec<String> {
+        match &self.kind {
+            GrammarKind::Phrases(phrases) => phrases.iter().map(|words| words.join(" ")).collect(),
+            GrammarKind::Srgs(srgs) => srgs.phrases().iter().map(|words| words.join(" ")).collect(),
+            GrammarKind::Digits | GrammarKind::Open => Vec::new(),
+        }
+    }
+
+    pub fn matches(&self, text: &str) -> GrammarMatch {
+        let words = normalize(text);
+        if words.is_empty() {
+            return GrammarMatch::None;
+        }
+        match &self.kind {
+            GrammarKind::Open => GrammarMatch::Complete,
+            GrammarKind::Digits => {
+                if words.iter().all(|w| w.chars().all(|c| c.is_ascii_digit())) {
+                    GrammarMatch::Complete
+                } else {
+                    GrammarMatch::None
+                }
+            }
+            GrammarKind::Phrases(phrases) => {
+                let mut best = GrammarMatch::None;
+                for phrase in phrases {
+                    if *phrase == words {
+                        return GrammarMatch::Complete;
+                    }
+                    if phrase.starts_with(&words) {
+                        best = GrammarMatch::Incomplete;
+                    }
+                }
+                best
+            }
+            GrammarKind::Srgs(srgs) => srgs.matches(&words),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Grammars {
+    defined: HashMap<String, Grammar>,
+    active: Vec<Grammar>,
+}
+
+impl Grammars {
+    pub fn define(&mut self, id: &str, content_type: &str, body: &str) {
+        log::info!("Define grammar {:?} ({})", id, content_type);
+        self.defined.insert(
+            id.to_owned(),
+            Grammar::parse(&session_uri(id), content_type, body),
+        );
+    }
+
+    /// Selects grammars for the next recognition from a RECOGNIZE body:
+    /// either an inline grammar, known by its Content-Id, or a `text/uri-list`
+    /// of `session:` and `builtin:` URIs.
+    pub fn activate(&mut self, content_type: &str, content_id: &str, body: &str) {
+        self.active.clear();
+        if body.trim().is_empty() {
+            self.active.extend(self.defined.values().cloned());
+        } else if content_type.contains("uri-list") {
+            for uri in body.lines().map(str::trim).filter(|uri| !uri.is_empty()) {
+                if let Some(id) = uri.strip_prefix("session:") {
+                    match self.defined.get(id) {
+                        Some(grammar) => self.active.push(grammar.clone()),
+                        None => log::warn!("Undefined grammar {:?}", uri),
+                    }
+                } else if uri.starts_with("builtin:") {
+                    self.active.push(Grammar::builtin(uri));
+                } else {
+                    log::warn!("Unsupported grammar URI {:?}", uri);
+                }
+            }
+        } else {
+            let id = if content_id.is_empty() {
+                "inline"
+            } else {
+                content_id
+            };
+            self.active
+                .push(Grammar::parse(&session_uri(id), content_type, body));
+        }
+        log::debug!(
+            "Active grammars: {:?}",
+            self.active.iter().map(Grammar::id).collect::<Vec<_>>()
+        );
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.active
+            .iter()
+            .map(|grammar| grammar.id.clone())
+            .collect()
+    }
+
+    pub fn phrases(&self) -> Vec<String> {
+        self.active.iter().flat_map(Grammar::phrases).collect()
+    }
+
+    /// URI of the active grammar the text matches completely.
+    pub fn matched(&self, text: &str) -> Option<&str> {
+        self.active
+            .iter()
+            .find(|grammar| grammar.matches(text) == GrammarMatch::Complete)
+            .map(Grammar::id)
+    }
+
+    pub fn matches(&self, text: &str) -> GrammarMatch {
+        if self.active.is_empty() {
+            return if normalize(text).is_empty() {
+                GrammarMatch::None
+            } else {
+                GrammarMatch::Complete
+            };
+        }
+        let mut best = GrammarMatch::None;
+        for grammar in self.active.iter() {
+            match grammar.matches(text) {
+                GrammarMatch::Complete => return GrammarMatch::Complete,
+                GrammarMatch::Incomplete => best = GrammarMatch::Incomplete,
+                GrammarMatch::None => {}
+            }
+        }
+        best
+    }
+}
+
+fn session_uri(id: &str) -> String {
+    format!("session:{}", id)
+}
+
+fn normalize(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGITS_GRAMMAR: &str = r#"<grammar root="main"><rule id="main">
+        <one-of><item>один два три</item><item>четыре</item></one-of>
+    </rule></grammar>"#;
+
+    #[test]
+    fn matches_active_grammars() {
+        let mut grammars = Grammars::default();
+        grammars.define("digits", "application/srgs+xml", DIGITS_GRAMMAR);
+        grammars.activate(
+            "text/uri-list",
+            "",
+            "session:digits\nbuiltin:grammar/boolean",
+        );
+        assert_eq!(
+            grammars.ids(),
+            ["session:digits", "builtin:grammar/boolean"]
+        );
+        assert_eq!(grammars.matches("один два три"), GrammarMatch::Complete);
+        assert_eq!(grammars.matched("один два три"), Some("session:digits"));
+        assert_eq!(grammars.matched("Да."), Some("builtin:grammar/boolean"));
+        assert_eq!(grammars.matches("один два"), GrammarMatch::Incomplete);
+        assert_eq!(grammars.matches("пять"), GrammarMatch::None);
+    }
+
+    #[test]
+    fn inline_grammar_is_known_by_content_id() {
+        let mut grammars = Grammars::default();
+        grammars.activate(
+            "application/srgs+xml",
+            "request1@form-level",
+            DIGITS_GRAMMAR,
+        );
+        assert_eq!(
+            grammars.matched("четыре"),
+            Some("session:request1@form-level")
+        );
+        grammars.activate("application/srgs+xml", "", DIGITS_GRAMMAR);
+        assert_eq!(grammars.matched("четыре"), Some("session:inline"));
+    }
+
+    #[test]
+    fn without_active_grammars_any_words_match() {
+        let grammars = Grammars::default();
+        assert_eq!(grammars.matches("что угодно"), GrammarMatch::Complete);
+        assert_eq!(grammars.matches(" ... "), GrammarMatch::None);
+    }
+
+    #[test]
+    fn builtin_digits_match_digits_only() {
+        let digits = Grammar::builtin("builtin:grammar/digits?length=4");
+        assert_eq!(digits.matches("12 34"), GrammarMatch::Complete);
+        assert_eq!(digits.matches("12 ab"), GrammarMatch::None);
+    }
+
+    #[test]
+    fn grammar_without_rules_is_open() {
+        let grammar = Grammar::parse("empty", "application/srgs+xml", "<grammar/>");
+        assert_eq!(grammar.matches("что угодно"), GrammarMatch::Complete);
+        assert!(grammar.phrases().is_empty());
+    }
+
+    #[test]
+    fn phrases_of_srgs_grammar() {
+        let grammar = Grammar::parse("digits", "application/srgs+xml", DIGITS_GRAMMAR);
+        assert_eq!(grammar.phrases(), ["один два три", "четыре"]);
+    }
+}
@@ -0,0 +1,679 @@
+//! SRGS XML grammars as a tree of rules, matched against the recognized words.
+//!
+//! Sequences, `<one-of>`, `<item repeat>`, local `<ruleref uri="#id">` and the
+//! NULL, VOID and GARBAGE special rules are supported. Weights, `<tag>` and
+//! external rule references are not: an external reference matches any words.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::normalize;
+
+/// Nodes a grammar may have, larger ones are not parsed.
+const MAX_NODES: usize = 10_000;
+/// Node visits one match may take, a pathological grammar gives up with what it has.
+const MAX_STEPS: usize = 1_000_000;
+/// Phrases handed to the backend as hints, a grammar may describe many more.
+const MAX_PHRASES: usize = 100;
+/// Repeat counts beyond the minimum spelled out as hints.
+const MAX_REPEAT_EXPANSION: usize = 2;
+/// Position past the last word, reached by a path that needs more words.
+const PAST_END: usize = usize::MAX;
+
+/// Elements whose content is not spoken input.
+const SKIPPED: [&str; 5] = ["tag", "example", "meta", "metadata", "lexicon"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Token(String),
+    Sequence(Vec<Node>),
+    OneOf(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+    },
+    RuleRef(String),
+    Null,
+    Void,
+    Garbage,
+}
+
+#[derive(Debug, Clone)]
+pub struct Srgs {
+    rules: HashMap<String, Node>,
+    root: String,
+}
+
+type Positions = BTreeSet<usize>;
+
+impl Srgs {
+    /// `None` when the body has no rules.
+    pub fn parse(body: &str) -> Option<Self> {
+        let mut parser = Parser::default();
+        parser.run(body);
+        if parser.nodes > MAX_NODES {
+            log::warn!("Grammar has over {} nodes", MAX_NODES);
+            return None;
+        }
+        let root = parser.root.or(parser.first_rule)?;
+        if !parser.rules.contains_key(&root) {
+            log::warn!("Grammar root rule {:?} is not defined", root);
+            return None;
+        }
+        Some(Self {
+            rules: parser.rules,
+            root,
+        })
+    }
+
+    /// Matches normalized words against the root rule.
+    /// Rule references are matched once per start position, recursive ones are
+    /// repeated until their ends stop growing, so recursion costs a polynomial time.
+    pub fn matches(&self, words: &[String]) -> super::GrammarMatch {
+        let root = Node::RuleRef(self.root.clone());
+        let mut matching = Matching {
+            words,
+            ends: HashMap::new(),
+            visited: HashSet::new(),
+            grown: false,
+            steps: 0,
+        };
+        let ends = loop {
+            matching.visited.clear();
+            matching.grown = false;
+            let ends = self.advance(&root, &mut matching, &Positions::from([0]));
+            if !matching.grown {
+                break ends;
+            }
+            if matching.steps > MAX_STEPS {
+                log::warn!("Grammar {:?} takes too long to match", self.root);
+                break ends;
+            }
+        };
+        if ends.contains(&words.len()) {
+            super::GrammarMatch::Complete
+        } else if ends.contains(&PAST_END) {
+            super::GrammarMatch::Incomplete
+        } else {
+            super::GrammarMatch::None
+        }
+    }
+
+    /// Sentences of the grammar, up to `MAX_PHRASES`.
+    pub fn phrases(&self) -> Vec<Vec<String>> {
+        let mut phrases = self.expand(&Node::RuleRef(self.root.clone()), &mut HashMap::new());
+        phrases.retain(|words| !words.is_empty());
+        phrases.dedup();
+        phrases
+    }
+
+    /// Positions after `node` for each of the start positions.
+    fn advance<'a>(
+        &'a self,
+        node: &'a Node,
+        matching: &mut Matching<'a>,
+        starts: &Positions,
+    ) -> Positions {
+        matching.steps += 1;
+        let words = matching.words;
+        match node {
+            Node::Token(word) => starts
+                .iter()
+                .filter_map(|&position| {
+                    if position == PAST_END || position == words.len() {
+                        Some(PAST_END)
+                    } else {
+                        (words[position] == *word).then_some(position + 1)
+                    }
+                })
+                .collect(),
+            Node::Sequence(nodes) => nodes.iter().fold(starts.clone(), |positions, node| {
+                self.advance(node, matching, &positions)
+            }),
+            Node::OneOf(nodes) => nodes
+                .iter()
+                .flat_map(|node| self.advance(node, matching, starts))
+                .collect(),
+            Node::Repeat { node, min, max } => {
+                let mut ends = if *min == 0 {
+                    starts.clone()
+                } else {
+                    Positions::new()
+                };
+                let mut seen = starts.clone();
+                let mut current = starts.clone();
+                let mut count = 0;
+                while !current.is_empty() && !matches!(max, Some(max) if count >= *max) {
+                    current = self.advance(node, matching, &current);
+                    count += 1;
+                    if count >= *min {
+                        ends.extend(current.iter().copied());
+                        // Reaching a position again in more repeats leads nowhere new
+                        current.retain(|&position| seen.insert(position));
+                    }
+                }
+                ends
+            }
+            Node::RuleRef(id) => {
+                let Some(rule) = self.rules.get(id) else {
+                    return Positions::new();
+                };
+                let mut ends = Positions::new();
+                for &start in starts {
+                    let key = (id.as_str(), start);
+                    // A reference met again in this pass, recursive or not, takes
+                    // the ends known so far
+                    if matching.visited.insert(key) {
+                        let found = self.advance(rule, matching, &Positions::from([start]));
+                        let known = matching.ends.entry(key).or_default();
+                        if !found.is_subset(known) {
+                            known.extend(found);
+                            matching.grown = true;
+                        }
+                    }
+                    if let Some(known) = matching.ends.get(&key) {
+                        ends.extend(known.iter().copied());
+                    }
+                }
+                ends
+            }
+            Node::Null => starts.clone(),
+            Node::Void => Positions::new(),
+            Node::Garbage => starts
+                .iter()
+                .flat_map(|&position| {
+                    if position == PAST_END {
+                        position..=position
+                    } else {
+                        position..=words.len()
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Phrases of a node, each rule is expanded once and a recursive reference adds none.
+    fn expand<'a>(
+        &'a self,
+        node: &'a Node,
+        rules: &mut HashMap<&'a str, Option<Vec<Vec<String>>>>,
+    ) -> Vec<Vec<String>> {
+        match node {
+            Node::Token(word) => vec![vec![word.clone()]],
+            Node::Sequence(nodes) => nodes.iter().fold(vec![Vec::new()], |phrases, node| {
+                product(&phrases, &self.expand(node, rules))
+            }),
+            Node::OneOf(nodes) => {
+                let mut phrases = Vec::new();
+                for node in nodes {
+                    phrases.extend(self.expand(node, rules));
+                    if phrases.len() >= MAX_PHRASES {
+                        break;
+                    }
+                }
+                phrases.truncate(MAX_PHRASES);
+                phrases
+            }
+            Node::Repeat { node, min, max } => {
+                let once = self.expand(node, rules);
+                let upper = (*min + MAX_REPEAT_EXPANSION).min(max.unwrap_or(usize::MAX));
+                let mut phrases = Vec::new();
+                let mut repeated = vec![Vec::new()];
+                for count in 0..=upper {
+                    if count >= *min {
+                        phrases.extend(repeated.iter().cloned());
+                    }
+                    if phrases.len() >= MAX_PHRASES {
+                        break;
+                    }
+                    repeated = product(&repeated, &once);
+                }
+                phrases.truncate(MAX_PHRASES);
+                phrases
+            }
+            Node::RuleRef(id) => {
+                let Some(rule) = self.rules.get(id) else {
+                    return Vec::new();
+                };
+                match rules.get(id.as_str()) {
+                    Some(Some(phrases)) => return phrases.clone(),
+                    Some(None) => return Vec::new(),
+                    None => {}
+                }
+                rules.insert(id, None);
+                let phrases = self.expand(rule, rules);
+                rules.insert(id, Some(phrases.clone()));
+                phrases
+            }
+            Node::Null | Node::Garbage => vec![Vec::new()],
+            Node::Void => Vec::new(),
+        }
+    }
+}
+
+/// State of one `matches` call.
+struct Matching<'a> {
+    words: &'a [String],
+    /// Ends found so far for a rule from a start position.
+    ends: HashMap<(&'a str, usize), Positions>,
+    /// References matched in the current pass.
+    visited: HashSet<(&'a str, usize)>,
+    /// Whether the current pass found new ends.
+    grown: bool,
+    steps: usize,
+}
+
+fn product(heads: &[Vec<String>], tails: &[Vec<String>]) -> Vec<Vec<String>> {
+    let mut phrases = Vec::new();
+    for head in heads {
+        for tail in tails {
+            if phrases.len() >= MAX_PHRASES {
+                return phrases;
+            }
+            phrases.push(head.iter().chain(tail).cloned().collect());
+        }
+    }
+    phrases
+}
+
+/// An element being read, with the nodes of its content so far.
+#[derive(Debug)]
+struct Element {
+    name: String,
+    attributes: HashMap<String, String>,
+    children: Vec<Node>,
+    skipped: bool,
+}
+
+#[derive(Debug, Default)]
+struct Parser {
+    stack: Vec<Element>,
+    rules: HashMap<String, Node>,
+    root: Option<String>,
+    first_rule: Option<String>,
+    nodes: usize,
+}
+
+impl Parser {
+    fn run(&mut self, body: &str) {
+        let mut rest = body;
+        while let Some(start) = rest.find('<') {
+            self.text(&rest[..start]);
+            if self.nodes > MAX_NODES {
+                return;
+            }
+            let Some(end) = rest[start..].find('>') else {
+                break;
+            };
+            let tag = &rest[start + 1..start + end];
+            rest = &rest[start + end + 1..];
+            if tag.starts_with('?') || tag.starts_with('!') {
+                continue;
+            }
+            if let Some(name) = tag.strip_prefix('/') {
+                self.close(name.trim());
+                continue;
+            }
+            let self_closing = tag.ends_with('/');
+            let tag = tag.trim_end_matches('/');
+            let name = tag.split_whitespace().next().unwrap_or_default();
+            let skipped =
+                SKIPPED.contains(&name) || self.stack.last().is_some_and(|parent| parent.skipped);
+            self.stack.push(Element {
+                name: name.to_owned(),
+                attributes: attributes(&tag[name.len()..]),
+                children: Vec::new(),
+                skipped,
+            });
+            if self_closing {
+                self.close(name);
+            }
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Some(element) = self.stack.last_mut() {
+            if !element.skipped && matches!(element.name.as_str(), "item" | "rule" | "token") {
+                let tokens = normalize(&unescape(text));
+                self.nodes += tokens.len();
+                element.children.extend(tokens.into_iter().map(Node::Token));
+            }
+        }
+    }
+
+    fn close(&mut self, name: &str) {
+        let Some(position) = self.stack.iter().rposition(|element| element.name == name) else {
+            return;
+        };
+        self.stack.truncate(position + 1);
+        let Some(element) = self.stack.pop() else {
+            return;
+        };
+        if element.skipped {
+            return;
+        }
+        let node = match element.name.as_str() {
+            "grammar" => {
+                self.root = element.attributes.get("root").cloned();
+                return;
+            }
+            "rule" => {
+                let Some(id) = element.attributes.get("id") else {
+                    log::warn!("Grammar rule without id");
+                    return;
+                };
+                self.first_rule.get_or_insert_with(|| id.clone());
+                self.rules
+                    .insert(id.clone(), Node::Sequence(element.children));
+                return;
+            }
+            "item" => {
+                let node = Node::Sequence(element.children);
+                match element
+                    .attributes
+                    .get("repeat")
+                    .map(|repeat| repeat_range(repeat))
+                {
+                    Some(Some((1, Some(1)))) | None => node,
+                    Some(Some((min, max))) => Node::Repeat {
+                        node: Box::new(node),
+                        min,
+                        max,
+                    },
+                    Some(None) => {
+                        log::warn!("Invalid item repeat {:?}", element.attributes["repeat"]);
+                        node
+                    }
+                }
+            }
+            "one-of" => Node::OneOf(element.children),
+            "token" => Node::Sequence(element.children),
+            "ruleref" => ruleref(&element.attributes),
+            _ => Node::Sequence(element.children),
+        };
+        self.nodes += 1;
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children.push(node);
+        }
+    }
+}
+
+fn ruleref(attributes: &HashMap<String, String>) -> Node {
+    if let Some(special) = attributes.get("special") {
+        return match special.as_str() {
+            "NULL" => Node::Null,
+            "VOID" => Node::Void,
+            _ => Node::Garbage,
+        };
+    }
+    match attributes.get("uri") {
+        Some(uri) if uri.starts_with('#') => Node::RuleRef(uri[1..].to_owned()),
+        uri => {
+            log::warn!("External rule reference {:?} matches any words", uri);
+            Node::Garbage
+        }
+    }
+}
+
+/// `n`, `n-m` or `n-`.
+fn repeat_range(repeat: &str) -> Option<(usize, Option<usize>)> {
+    match repeat.split_once('-') {
+        None => {
+            let count = repeat.trim().parse().ok()?;
+            Some((count, Some(count)))
+        }
+        Some((min, "")) => Some((min.trim().parse().ok()?, None)),
+        Some((min, max)) => {
+            let (min, max) = (min.trim().parse().ok()?, max.trim().parse().ok()?);
+            (min <= max).then_some((min, Some(max)))
+        }
+    }
+}
+
+fn attributes(mut rest: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals].trim();
+        let value = rest[equals + 1..].trim_start();
+        let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            break;
+        };
+        let Some(end) = value[1..].find(quote) else {
+            break;
+        };
+        attributes.insert(name.to_owned(), unescape(&value[1..end + 1]));
+        rest = &value[end + 2..];
+    }
+    attributes
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::GrammarMatch;
+
+    fn matches(grammar: &str, text: &str) -> GrammarMatch {
+        Srgs::parse(grammar).unwrap().matches(&normalize(text))
+    }
+
+    const ONE_OF: &str = r#"<?xml version="1.0"?>
+        <grammar root="answer" xmlns="http://www.w3.org/2001/06/grammar">
+          <rule id="answer">
+            <one-of>
+              <item>да</item>
+              <item>нет <tag>out="no"</tag></item>
+              <item>позовите оператора</item>
+            </one-of>
+          </rule>
+        </grammar>"#;
+
+    #[test]
+    fn matches_one_of_items() {
+        assert_eq!(matches(ONE_OF, "Да!"), GrammarMatch::Complete);
+        assert_eq!(matches(ONE_OF, "нет"), GrammarMatch::Complete);
+        assert_eq!(
+            matches(ONE_OF, "позовите оператора"),
+            GrammarMatch::Complete
+        );
+        assert_eq!(matches(ONE_OF, "позовите"), GrammarMatch::Incomplete);
+        assert_eq!(matches(ONE_OF, "может быть"), GrammarMatch::None);
+        assert_eq!(matches(ONE_OF, "да нет"), GrammarMatch::None);
+    }
+
+    #[test]
+    fn matches_sequence_around_one_of() {
+        let grammar = r#"<grammar root="order"><rule id="order">
+            хочу <one-of><item>чай</item><item>кофе</item></one-of> пожалуйста
+        </rule></grammar>"#;
+        assert_eq!(
+            matches(grammar, "хочу кофе пожалуйста"),
+            GrammarMatch::Complete
+        );
+        assert_eq!(matches(grammar, "хочу чай"), GrammarMatch::Incomplete);
+        assert_eq!(matches(grammar, "хочу"), GrammarMatch::Incomplete);
+        assert_eq!(matches(grammar, "хочу сок пожалуйста"), GrammarMatch::None);
+    }
+
+    #[test]
+    fn matches_item_repeat() {
+        let grammar = r#"<grammar root="pin"><rule id="pin">
+            код <item repeat="2-3"><one-of><item>один</item><item>два</item></one-of></item>
+            <item repeat="0-1">готово</item>
+        </rule></grammar>"#;
+        assert_eq!(matches(grammar, "код один"), GrammarMatch::Incomplete);
+        assert_eq!(matches(grammar, "код один два"), GrammarMatch::Complete);
+        assert_eq!(
+            matches(grammar, "код один два один"),
+            GrammarMatch::Complete
+        );
+        assert_eq!(
+            matches(grammar, "код два два готово"),
+            GrammarMatch::Complete
+        );
+        assert_eq!(
+            matches(grammar, "код один два один два"),
+            GrammarMatch::None
+        );
+    }
+
+    #[test]
+    fn matches_unbounded_repeat() {
+        let grammar = r#"<grammar root="digits"><rule id="digits">
+            <item repeat="1-"><one-of><item>один</item><item>два</item></one-of></item>
+        </rule></grammar>"#;
+        assert_eq!(
+            matches(grammar, "один два один два один"),
+            GrammarMatch::Complete
+        );
+        assert_eq!(matches(grammar, "один три"), GrammarMatch::None);
+    }
+
+    #[test]
+    fn matches_rule_references() {
+        let grammar = r##"<grammar root="transfer">
+            <rule id="transfer">переведите <ruleref uri="#amount"/> рублей</rule>
+            <rule id="amount"><one-of><item>сто</item><item>двести</item></one-of></rule>
+        </grammar>"##;
+        assert_eq!(
+            matches(grammar, "переведите сто рублей"),
+            GrammarMatch::Complete
+        );
+        assert_eq!(
+            matches(grammar, "переведите двести"),
+            GrammarMatch::Incomplete
+        );
+        assert_eq!(
+            matches(grammar, "переведите триста рублей"),
+            GrammarMatch::None
+        );
+    }
+
+    #[test]
+    fn matches_special_rules() {
+        let grammar = r#"<grammar root="main"><rule id="main">
+            <ruleref special="GARBAGE"/> оператор <ruleref special="NULL"/>
+        </rule></grammar>"#;
+        assert_eq!(
+            matches(grammar, "мне нужен оператор"),
+            GrammarMatch::Complete
+        );
+        assert_eq!(matches(grammar, "оператор"), GrammarMatch::Complete);
+        let void =
+            r#"<grammar root="main"><rule id="main">да <ruleref special="VOID"/></rule></grammar>"#;
+        assert_eq!(matches(void, "да"), GrammarMatch::None);
+    }
+
+    #[test]
+    fn recursive_rule_does_not_overflow() {
+        let grammar = r##"<grammar root="loop"><rule id="loop">
+            <one-of><item>да</item><item><ruleref uri="#loop"/> да</item></one-of>
+        </rule></grammar>"##;
+        assert_eq!(matches(grammar, "да да"), GrammarMatch::Complete);
+        assert_eq!(matches(grammar, "нет"), GrammarMatch::None);
+    }
+
+    #[test]
+    fn matches_deep_recursion() {
+        let left = r##"<grammar root="loop"><rule id="loop">
+            <one-of><item>да</item><item><ruleref uri="#loop"/> да</item></one-of>
+        </rule></grammar>"##;
+        let right = r##"<grammar root="list"><rule id="list">
+            один <item repeat="0-1"><ruleref uri="#list"/></item>
+        </rule></grammar>"##;
+        let many = vec!["да"; 200].join(" ");
+        assert_eq!(matches(left, &many), GrammarMatch::Complete);
+        assert_eq!(matches(left, &format!("{} нет", many)), GrammarMatch::None);
+        let many = vec!["один"; 200].join(" ");
+        assert_eq!(matches(right, &many), GrammarMatch::Complete);
+        assert_eq!(matches(right, ""), GrammarMatch::Incomplete);
+    }
+
+    #[test]
+    fn matches_branching_recursion_in_polynomial_time() {
+        // Each rule refers to the next twice, unfolding gives 2^40 paths
+        let mut grammar = String::from(r#"<grammar root="r0">"#);
+        for n in 0..40 {
+            grammar.push_str(&format!(
+                r##"<rule id="r{n}"><one-of><item><ruleref uri="#r{m}"/></item>
+                <item><ruleref uri="#r{m}"/> <ruleref uri="#r{m}"/></item></one-of></rule>"##,
+                n = n,
+                m = n + 1
+            ));
+        }
+        grammar.push_str(
+            r##"<rule id="r40"><one-of><item>да</item><item><ruleref uri="#r40"/> <ruleref uri="#r40"/></item></one-of></rule></grammar>"##,
+        );
+        let grammar = Srgs::parse(&grammar).unwrap();
+        let started = std::time::Instant::now();
+        assert_eq!(
+            grammar.matches(&normalize(&vec!["да"; 30].join(" "))),
+            GrammarMatch::Complete
+        );
+        assert_eq!(grammar.matches(&normalize("да нет")), GrammarMatch::None);
+        assert_eq!(
+            grammar
+                .phrases()
+                .into_iter()
+                .map(|words| words.join(" "))
+                .next(),
+            Some("да".to_owned())
+        );
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn rejects_oversized_grammar() {
+        let words = vec!["да"; MAX_NODES + 1].join(" ");
+        let grammar = format!(
+            r#"<grammar root="main"><rule id="main">{}</rule></grammar>"#,
+            words
+        );
+        assert!(Srgs::parse(&grammar).is_none());
+        let grammar = format!(
+            r#"<grammar root="main"><rule id="main">{}</rule></grammar>"#,
+            vec!["да"; 100].join(" ")
+        );
+        assert!(Srgs::parse(&grammar).is_some());
+    }
+
+    #[test]
+    fn root_defaults_to_first_rule() {
+        let grammar =
+            r#"<grammar><rule id="first">раз</rule><rule id="second">два</rule></grammar>"#;
+        assert_eq!(matches(grammar, "раз"), GrammarMatch::Complete);
+        assert_eq!(matches(grammar, "два"), GrammarMatch::None);
+        assert!(Srgs::parse("<grammar/>").is_none());
+    }
+
+    #[test]
+    fn expands_phrases() {
+        let grammar = r##"<grammar root="transfer">
+            <rule id="transfer">переведите <ruleref uri="#amount"/></rule>
+            <rule id="amount"><one-of><item>сто</item><item>двести</item></one-of></rule>
+        </grammar>"##;
+        let phrases: Vec<_> = Srgs::parse(grammar)
+            .unwrap()
+            .phrases()
+            .into_iter()
+            .map(|words| words.join(" "))
+            .collect();
+        assert_eq!(phrases, ["переведите сто", "переведите двести"]);
+    }
+
+    #[test]
+    fn parses_repeat_ranges() {
+        assert_eq!(repeat_range("3"), Some((3, Some(3))));
+        assert_eq!(repeat_range("0-1"), Some((0, Some(1))));
+        assert_eq!(repeat_range("2-"), Some((2, None)));
+        assert_eq!(repeat_range("3-1"), None);
+        assert_eq!(repeat_range("x"), None);
+    }
+}
@@ -1,14 +1,38 @@
 #![allow(clippy::missing_safety_doc)]
-use std::{io::Write, mem::size_of};
+use std::{
+    io::Write,
+    mem::size_of,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, Ordering},
+        Mutex,
+    },
+    thread::JoinHandle,
+};
 
-use recog_buffer::RecogBuffer;
+use backend::Backend;
+use config::EngineConfig;
+use recog_buffer::{RecogBuffer, RecognitionOutcome};
 use speech_detector::SpeechDetectorEvent;
 
+mod audio_quality;
+mod backend;
+mod config;
+mod debug_tap;
+mod dsp;
+mod fft;
+mod grammar;
+mod nlsml;
 mod recog_buffer;
+mod recog_params;
+mod resampler;
 mod speech_detector;
 pub mod uni;
+mod utterance;
+mod wav;
+mod waveform_writer;
 
 const RECOG_ENGINE_TASK_NAME: &[u8; 16] = b"Rust ASR-Engine\0";
+const RESULT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
 
 pub static ENGINE_VTABLE: uni::mrcp_engine_method_vtable_t = uni::mrcp_engine_method_vtable_t {
     destroy: Some(engine_destroy),
@@ -39,18 +63,34 @@ static STREAM_VTABLE: uni::mpf_audio_stream_vtable_t = uni::mpf_audio_stream_vta
 #[repr(C)]
 struct DemoRecogEngine {
     task: *mut uni::apt_consumer_task_t,
+    config: *mut EngineConfig,
+    backend: *mut Backend,
 }
 
+/// `pending_request`, `recog_request`, `stop_response` and `waveform_input` are shared by
+/// the engine task, the media thread and the waveform thread. The engine task only
+/// prepares a RECOGNIZE, the media thread starts it once it is between frames.
 #[derive(Debug)]
 #[repr(C)]
 struct DemoRecogChannel {
     custom_engine: *mut DemoRecogEngine,
     channel: *mut uni::mrcp_engine_channel_t,
-    recog_request: *mut uni::mrcp_message_t,
-    stop_response: *mut uni::mrcp_message_t,
+    pending_request: AtomicPtr<uni::mrcp_message_t>,
+    recog_request: AtomicPtr<uni::mrcp_message_t>,
+    stop_response: AtomicPtr<uni::mrcp_message_t>,
     audio_buffer: *mut RecogBuffer,
+    /// Set while the waveform thread owns the buffer, the media thread leaves it alone.
+    waveform_input: AtomicBool,
+    /// Recognizes the Input-Waveform-URI recording, started on the media thread and
+    /// joined on the engine task.
+    waveform_thread: Mutex<Option<JoinHandle<()>>>,
 }
 
+/// Hands the channel to its waveform thread, which is joined before the channel goes away.
+struct ChannelPtr(*mut DemoRecogChannel);
+
+unsafe impl Send for ChannelPtr {}
+
 #[repr(C)]
 enum RecogMsgType {
     OpenChannel,
@@ -82,6 +122,8 @@ pub unsafe extern "C" fn mrcp_plugin_create(pool: *mut uni::apr_pool_t) -> *mut
     );
 
     let custom_engine = uni::apr_palloc(pool, size_of::<DemoRecogEngine>()) as *mut DemoRecogEngine;
+    (*custom_engine).config = std::ptr::null_mut() as _;
+    (*custom_engine).backend = std::ptr::null_mut() as _;
     let msg_pool = uni::apt_task_msg_pool_create_dynamic(size_of::<RecogMsg>(), pool);
     (*custom_engine).task = uni::apt_consumer_task_create(custom_engine as _, msg_pool, pool);
     if (*custom_engine).task.is_null() {
@@ -116,6 +158,14 @@ unsafe extern "C" fn engine_destroy(engine: *mut uni::mrcp_engine_t) -> uni::apt
         (*custom_engine).task = std::ptr::null_mut() as _;
         log::debug!("[DEMO_RECOG] Task {:?} destroyed = {:?}", task, destroyed);
     }
+    if !(*custom_engine).config.is_null() {
+        EngineConfig::destroy((*custom_engine).config);
+        (*custom_engine).config = std::ptr::null_mut() as _;
+    }
+    if !(*custom_engine).backend.is_null() {
+        drop(Box::from_raw((*custom_engine).backend));
+        (*custom_engine).backend = std::ptr::null_mut() as _;
+    }
     uni::TRUE
 }
 
@@ -126,6 +176,18 @@ unsafe extern "C" fn engine_open(engine: *mut uni::mrcp_engine_t) -> uni::apt_bo
         engine,
         custom_engine
     );
+    if (*custom_engine).config.is_null() {
+        (*custom_engine).config = EngineConfig::leaked(engine);
+    }
+    if (*custom_engine).backend.is_null() {
+        match backend::create(&*(*custom_engine).config) {
+            Ok(backend) => (*custom_engine).backend = Box::into_raw(Box::new(backend)),
+            Err(e) => {
+                log::error!("[DEMO_RECOG] Unable to open engine: {}", e);
+                return uni::inline_mrcp_engine_open_respond(engine, uni::FALSE);
+            }
+        }
+    }
     if !(*custom_engine).task.is_null() {
         let task = uni::apt_consumer_task_base_get((*custom_engine).task);
         let started = uni::apt_task_start(task);
@@ -163,17 +225,24 @@ unsafe extern "C" fn engine_create_channel(
         engine
     );
 
+    let custom_engine = (*engine).obj as *mut DemoRecogEngine;
     let demo_channel =
         uni::apr_palloc(pool, size_of::<DemoRecogChannel>()) as *mut DemoRecogChannel;
-    (*demo_channel).custom_engine = (*engine).obj as _;
-    (*demo_channel).recog_request = std::ptr::null_mut() as _;
-    (*demo_channel).stop_response = std::ptr::null_mut() as _;
-    (*demo_channel).audio_buffer = RecogBuffer::leaked();
+    (*demo_channel).custom_engine = custom_engine;
+    (*demo_channel).pending_request = AtomicPtr::new(std::ptr::null_mut());
+    (*demo_channel).recog_request = AtomicPtr::new(std::ptr::null_mut());
+    (*demo_channel).stop_response = AtomicPtr::new(std::ptr::null_mut());
+    (*demo_channel).audio_buffer = RecogBuffer::leaked(
+        &*(*custom_engine).config,
+        (*(*custom_engine).backend).clone(),
+    );
+    (*demo_channel).waveform_input = AtomicBool::new(false);
+    std::ptr::addr_of_mut!((*demo_channel).waveform_thread).write(Mutex::new(None));
 
     let capabilities = uni::inline_mpf_sink_stream_capabilities_create(pool);
     uni::inline_mpf_codec_capabilities_add(
         &mut (*capabilities).codecs as _,
-        uni::MPF_SAMPLE_RATE_8000 as _,
+        sample_rates_mask(&(*(*custom_engine).config).sample_rates) as _,
         b"LPCM\0".as_ptr() as _,
     );
 
@@ -197,27 +266,52 @@ unsafe extern "C" fn engine_create_channel(
     (*demo_channel).channel
 }
 
+fn sample_rates_mask(sample_rates: &[usize]) -> u32 {
+    let mut mask = 0;
+    for rate in sample_rates {
+        mask |= match rate {
+            8000 => uni::MPF_SAMPLE_RATE_8000,
+            16000 => uni::MPF_SAMPLE_RATE_16000,
+            32000 => uni::MPF_SAMPLE_RATE_32000,
+            48000 => uni::MPF_SAMPLE_RATE_48000,
+            _ => {
+                log::warn!("Unsupported sample rate {}", rate);
+                0
+            }
+        };
+    }
+    if mask == 0 {
+        uni::MPF_SAMPLE_RATE_8000
+    } else {
+        mask
+    }
+}
+
 pub unsafe extern "C" fn channel_destroy(
     channel: *mut uni::mrcp_engine_channel_t,
 ) -> uni::apt_bool_t {
     log::debug!("[DEMO_RECOG] Channel {:?} destroy.", channel);
     let demo_channel = (*channel).method_obj as *mut DemoRecogChannel;
+    (*demo_channel)
+        .pending_request
+        .store(std::ptr::null_mut(), Ordering::Release);
+    (*demo_channel)
+        .recog_request
+        .store(std::ptr::null_mut(), Ordering::Release);
+    demo_recog_waveform_join(demo_channel);
+    std::ptr::drop_in_place(std::ptr::addr_of_mut!((*demo_channel).waveform_thread));
     RecogBuffer::destroy((*demo_channel).audio_buffer);
     uni::TRUE
 }
 
 pub unsafe extern "C" fn channel_open(channel: *mut uni::mrcp_engine_channel_t) -> uni::apt_bool_t {
     log::debug!("[DEMO_RECOG] Channel {:?} open.", channel);
-    if !(*channel).attribs.is_null() {
-        let header = uni::apr_table_elts((*channel).attribs);
-        let entry = (*header).elts as *mut uni::apr_table_entry_t;
-        for i in 0..(*header).nelts {
-            let entry = entry.offset(i as _);
-            let key = std::ffi::CStr::from_ptr((*entry).key);
-            let val = std::ffi::CStr::from_ptr((*entry).val);
-            log::info!("Attrib name {:?} value {:?}", key, val);
-        }
+    let attribs = config::table_entries((*channel).attribs);
+    for (key, val) in attribs.iter() {
+        log::info!("Attrib name {:?} value {:?}", key, val);
     }
+    let demo_channel = (*channel).method_obj as *mut DemoRecogChannel;
+    (*(*demo_channel).audio_buffer).configure(&attribs);
     demo_recog_msg_signal(
         RecogMsgType::OpenChannel,
         channel,
@@ -259,22 +353,174 @@ unsafe fn demo_recog_channel_recognize(
         (*response).start_line.status_code = uni::MRCP_STATUS_CODE_METHOD_FAILED;
         return uni::FALSE;
     }
-    (*(*demo_channel).audio_buffer).prepare(request);
+    if demo_recog_active(demo_channel)
+        || !(*demo_channel)
+            .pending_request
+            .load(Ordering::Acquire)
+            .is_null()
+    {
+        log::warn!(
+            "RECOGNIZE while a recognition is in progress on {:?}",
+            channel
+        );
+        (*response).start_line.status_code = uni::MRCP_STATUS_CODE_METHOD_NOT_VALID;
+        return uni::FALSE;
+    }
+    // The previous recording must be done with the buffer before it is prepared again
+    demo_recog_waveform_join(demo_channel);
+    (*(*demo_channel).audio_buffer).prepare(request, (*descriptor).sampling_rate as _);
 
     (*response).start_line.request_state = uni::MRCP_REQUEST_STATE_INPROGRESS;
     uni::inline_mrcp_engine_channel_message_send(channel, response);
-
-    (*demo_channel).recog_request = request;
+    (*demo_channel)
+        .pending_request
+        .store(request, Ordering::Release);
     uni::TRUE
 }
 
+/// Starts a prepared RECOGNIZE on the media thread, where the buffer is written.
+unsafe fn demo_recog_start(demo_channel: *mut DemoRecogChannel, request: *mut uni::mrcp_message_t) {
+    (*(*demo_channel).audio_buffer).start();
+    let waveform_uri = (*(*demo_channel).audio_buffer)
+        .input_waveform_uri()
+        .map(str::to_owned);
+
+    // Keep stream_write off the buffer while the recording is processed
+    (*demo_channel)
+        .waveform_input
+        .store(waveform_uri.is_some(), Ordering::Release);
+    (*demo_channel)
+        .recog_request
+        .store(request, Ordering::Release);
+    if let Some(uri) = waveform_uri {
+        let channel_ptr = ChannelPtr(demo_channel);
+        let spawned = std::thread::Builder::new()
+            .name("waveform".to_owned())
+            .spawn(move || {
+                let channel_ptr = channel_ptr;
+                demo_recog_waveform_run(channel_ptr.0, &uri);
+            });
+        match spawned {
+            Ok(handle) => {
+                *(*demo_channel).waveform_thread.lock().unwrap() = Some(handle);
+            }
+            Err(e) => {
+                log::error!("Unable to start waveform recognition: {}", e);
+                (*demo_channel)
+                    .waveform_input
+                    .store(false, Ordering::Release);
+                demo_recog_complete(demo_channel, uni::RECOGNIZER_COMPLETION_CAUSE_ERROR, "");
+            }
+        }
+    }
+}
+
+/// Waits for the recording of a finished or stopped request to let go of the buffer.
+unsafe fn demo_recog_waveform_join(demo_channel: *mut DemoRecogChannel) {
+    let handle = (*demo_channel).waveform_thread.lock().unwrap().take();
+    if let Some(handle) = handle {
+        handle.join().ok();
+    }
+}
+
+/// Body of the waveform thread, it completes the request itself.
+unsafe fn demo_recog_waveform_run(demo_channel: *mut DemoRecogChannel, uri: &str) {
+    match wav::read_uri(uri) {
+        Ok((samples, sample_rate)) => {
+            demo_recog_waveform_process(demo_channel, &samples, sample_rate);
+        }
+        Err(e) => {
+            log::error!("Unable to read Input-Waveform-URI {:?}: {}", uri, e);
+            demo_recog_complete(
+                demo_channel,
+                uni::RECOGNIZER_COMPLETION_CAUSE_URI_FAILURE,
+                "",
+            );
+        }
+    }
+    (*(*demo_channel).audio_buffer).cancel();
+    (*demo_channel)
+        .waveform_input
+        .store(false, Ordering::Release);
+}
+
+/// Runs a recording through the same detector and recognizer path as RTP audio.
+unsafe fn demo_recog_waveform_process(
+    demo_channel: *mut DemoRecogChannel,
+    samples: &[i16],
+    sample_rate: usize,
+) {
+    log::info!(
+        "Recognize {} ms from Input-Waveform-URI",
+        samples.len() * 1000 / sample_rate.max(1)
+    );
+    let audio_buffer = (*demo_channel).audio_buffer;
+    (*audio_buffer).set_sample_rate(sample_rate);
+    let mut frame = Vec::new();
+    for chunk in samples.chunks((sample_rate / 100).max(1)) {
+        if !demo_recog_active(demo_channel) {
+            break;
+        }
+        frame.clear();
+        frame.extend(chunk.iter().flat_map(|sample| sample.to_ne_bytes()));
+        (*audio_buffer).write(&frame).ok();
+        demo_recog_recognition_process(demo_channel, (*audio_buffer).detector_event());
+        demo_recog_result_wait(demo_channel);
+    }
+    if demo_recog_active(demo_channel) {
+        (*audio_buffer).end_of_input();
+        demo_recog_recognition_process(demo_channel, (*audio_buffer).detector_event());
+        demo_recog_result_wait(demo_channel);
+    }
+}
+
+unsafe fn demo_recog_active(demo_channel: *mut DemoRecogChannel) -> bool {
+    !(*demo_channel)
+        .recog_request
+        .load(Ordering::Acquire)
+        .is_null()
+}
+
+/// Recordings are not paced by RTP, so the waveform thread waits for the recognizer
+/// before feeding the rest of the file.
+unsafe fn demo_recog_result_wait(demo_channel: *mut DemoRecogChannel) {
+    while demo_recog_active(demo_channel)
+        && (*(*demo_channel).audio_buffer).detector_event() == SpeechDetectorEvent::Recognizing
+    {
+        std::thread::sleep(RESULT_POLL_INTERVAL);
+        demo_recog_recognition_process(demo_channel, SpeechDetectorEvent::Recognizing);
+    }
+}
+
+unsafe fn demo_recog_channel_set_params(
+    channel: *mut uni::mrcp_engine_channel_t,
+    request: *mut uni::mrcp_message_t,
+    response: *mut uni::mrcp_message_t,
+) -> uni::apt_bool_t {
+    let demo_channel = (*channel).method_obj as *mut DemoRecogChannel;
+    (*(*demo_channel).audio_buffer).set_params(request);
+    uni::inline_mrcp_engine_channel_message_send(channel, response)
+}
+
+unsafe fn demo_recog_channel_define_grammar(
+    channel: *mut uni::mrcp_engine_channel_t,
+    request: *mut uni::mrcp_message_t,
+    response: *mut uni::mrcp_message_t,
+) -> uni::apt_bool_t {
+    let demo_channel = (*channel).method_obj as *mut DemoRecogChannel;
+    (*(*demo_channel).audio_buffer).define_grammar(request);
+    uni::inline_mrcp_engine_channel_message_send(channel, response)
+}
+
 unsafe fn demo_recog_channel_stop(
     channel: *mut uni::mrcp_engine_channel_t,
     _request: *mut uni::mrcp_message_t,
     response: *mut uni::mrcp_message_t,
 ) -> uni::apt_bool_t {
     let demo_channel = (*channel).method_obj as *mut DemoRecogChannel;
-    (*demo_channel).stop_response = response;
+    (*demo_channel)
+        .stop_response
+        .store(response, Ordering::Release);
     uni::TRUE
 }
 
@@ -295,9 +541,13 @@ unsafe fn demo_recog_channel_request_dispatch(
     let mut processed = uni::FALSE;
     let response = uni::mrcp_response_create(request, (*request).pool);
     match (*request).start_line.method_id as u32 {
-        uni::RECOGNIZER_SET_PARAMS => {}
+        uni::RECOGNIZER_SET_PARAMS => {
+            processed = demo_recog_channel_set_params(channel, request, response);
+        }
         uni::RECOGNIZER_GET_PARAMS => {}
-        uni::RECOGNIZER_DEFINE_GRAMMAR => {}
+        uni::RECOGNIZER_DEFINE_GRAMMAR => {
+            processed = demo_recog_channel_define_grammar(channel, request, response);
+        }
         uni::RECOGNIZER_RECOGNIZE => {
             processed = demo_recog_channel_recognize(channel, request, response);
         }
@@ -323,21 +573,36 @@ pub unsafe extern "C" fn stream_destroy(_stream: *mut uni::mpf_audio_stream_t) -
 }
 
 pub unsafe extern "C" fn stream_open(
-    _stream: *mut uni::mpf_audio_stream_t,
+    stream: *mut uni::mpf_audio_stream_t,
     _codec: *mut uni::mpf_codec_t,
 ) -> uni::apt_bool_t {
+    let demo_channel = (*stream).obj as *mut DemoRecogChannel;
+    let channel = (*demo_channel).channel;
+    let descriptor = uni::mrcp_engine_sink_stream_codec_get(channel);
+    if !descriptor.is_null() {
+        (*(*demo_channel).audio_buffer).open_debug_tap(
+            &recog_params::apt_string(&(*channel).id),
+            (*descriptor).sampling_rate as _,
+        );
+    }
     uni::TRUE
 }
 
-pub unsafe extern "C" fn stream_close(_stream: *mut uni::mpf_audio_stream_t) -> uni::apt_bool_t {
+pub unsafe extern "C" fn stream_close(stream: *mut uni::mpf_audio_stream_t) -> uni::apt_bool_t {
+    let demo_channel = (*stream).obj as *mut DemoRecogChannel;
+    (*(*demo_channel).audio_buffer).close_debug_tap();
     uni::TRUE
 }
 
 unsafe fn demo_recog_start_of_input(recog_channel: *mut DemoRecogChannel) -> uni::apt_bool_t {
+    let request = (*recog_channel).recog_request.load(Ordering::Acquire);
+    if request.is_null() {
+        return uni::FALSE;
+    }
     let message = uni::mrcp_event_create(
-        (*recog_channel).recog_request,
+        request,
         uni::RECOGNIZER_START_OF_INPUT as _,
-        (*(*recog_channel).recog_request).pool,
+        (*request).pool,
     );
     if message.is_null() {
         log::error!("Unable to create event START OF INPUT");
@@ -355,7 +620,7 @@ unsafe fn demo_recog_result_load(
     if !generic_header.is_null() {
         uni::inline_apt_string_assign(
             &mut (*generic_header).content_type as _,
-            b"text/plain; charset=UTF-8\0".as_ptr() as _,
+            b"application/nlsml+xml\0".as_ptr() as _,
             (*message).pool,
         );
         uni::mrcp_generic_header_property_add(message, uni::GENERIC_HEADER_CONTENT_TYPE as _);
@@ -383,6 +648,10 @@ unsafe fn demo_recog_recognition_process(
             log::trace!("Detected Voice Activity in {:?}", (*recog_channel).channel);
             if !(*(*recog_channel).audio_buffer).input_started() {
                 (*(*recog_channel).audio_buffer).start_input();
+                // Speech in hotword mode must not barge in until the hotword matches
+                if (*(*recog_channel).audio_buffer).hotword() {
+                    return uni::TRUE;
+                }
                 return demo_recog_start_of_input(recog_channel);
             } else {
                 return uni::TRUE;
@@ -402,9 +671,12 @@ unsafe fn demo_recog_recognition_process(
                 "Detected Duration Timeout in {:?}",
                 (*recog_channel).channel
             );
-            (*(*recog_channel).audio_buffer)
-                .recognize((*(*recog_channel).audio_buffer).duration_timeout());
-            return uni::TRUE;
+            if (*(*recog_channel).audio_buffer).recognize_on_timeout() {
+                (*(*recog_channel).audio_buffer)
+                    .recognize((*(*recog_channel).audio_buffer).duration_timeout());
+                return uni::TRUE;
+            }
+            uni::RECOGNIZER_COMPLETION_CAUSE_RECOGNITION_TIMEOUT
         }
         SpeechDetectorEvent::Noinput => {
             log::error!("Detected Noinput. Channel {:?}", (*recog_channel).channel);
@@ -412,19 +684,45 @@ unsafe fn demo_recog_recognition_process(
         }
         SpeechDetectorEvent::Recognizing => match (*(*recog_channel).audio_buffer).load_result() {
             None => return uni::FALSE,
-            Some(result) => {
+            Some(RecognitionOutcome::KeepListening) => {
+                (*(*recog_channel).audio_buffer).restart_writing();
+                return uni::FALSE;
+            }
+            Some(RecognitionOutcome::Success(result)) => {
+                // Speech was held back from barge-in until it turned out to be the hotword
+                if (*(*recog_channel).audio_buffer).hotword() {
+                    demo_recog_start_of_input(recog_channel);
+                }
                 recognized = result;
                 uni::RECOGNIZER_COMPLETION_CAUSE_SUCCESS
             }
+            Some(RecognitionOutcome::NoMatch) => uni::RECOGNIZER_COMPLETION_CAUSE_NO_MATCH,
+            Some(RecognitionOutcome::Error) => uni::RECOGNIZER_COMPLETION_CAUSE_ERROR,
         },
     };
+    demo_recog_complete(recog_channel, cause, &recognized)
+}
+
+unsafe fn demo_recog_complete(
+    recog_channel: *mut DemoRecogChannel,
+    cause: uni::mrcp_recog_completion_cause_e,
+    recognized: &str,
+) -> uni::apt_bool_t {
+    // Whoever takes the request completes it, a STOP may have got it first
+    let request = (*recog_channel)
+        .recog_request
+        .swap(std::ptr::null_mut(), Ordering::AcqRel);
+    if request.is_null() {
+        return uni::FALSE;
+    }
     let message = uni::mrcp_event_create(
-        (*recog_channel).recog_request,
+        request,
         uni::RECOGNIZER_RECOGNITION_COMPLETE as _,
-        (*(*recog_channel).recog_request).pool,
+        (*request).pool,
     );
     if message.is_null() {
         log::error!("Unable to create event RECOGNITION COMPLETE");
+        (*(*recog_channel).audio_buffer).cancel();
         return uni::FALSE;
     }
     let recog_header =
@@ -438,11 +736,7 @@ unsafe fn demo_recog_recognition_process(
     }
     (*message).start_line.request_state = uni::MRCP_REQUEST_STATE_COMPLETE;
     if cause == uni::RECOGNIZER_COMPLETION_CAUSE_SUCCESS {
-        if recognized.is_empty() {
-            (*(*recog_channel).audio_buffer).restart_writing();
-            return uni::FALSE;
-        }
-        demo_recog_result_load(recognized.as_str(), message);
+        demo_recog_result_load(recognized, message);
         log::info!(
             "[DEMO_RECOG] Load for {:?}: {:?} ({} bytes)",
             (*recog_channel).channel,
@@ -450,25 +744,103 @@ unsafe fn demo_recog_recognition_process(
             recognized.as_bytes().len()
         );
     }
-    (*recog_channel).recog_request = std::ptr::null_mut() as _;
+    if let Some(quality) = (*(*recog_channel).audio_buffer).audio_quality_params() {
+        demo_recog_vendor_params_add(message, &quality.params());
+    }
+    // No input leaves nothing worth saving
+    let waveform_uri = if cause == uni::RECOGNIZER_COMPLETION_CAUSE_NO_INPUT_TIMEOUT {
+        None
+    } else {
+        (*(*recog_channel).audio_buffer).save_waveform()
+    };
+    if let Some(waveform_uri) = waveform_uri {
+        if !recog_header.is_null() {
+            uni::inline_apt_string_assign_n(
+                &mut (*recog_header).waveform_uri as _,
+                waveform_uri.as_ptr() as _,
+                waveform_uri.len(),
+                (*message).pool,
+            );
+            uni::mrcp_resource_header_property_add(
+                message,
+                uni::RECOGNIZER_HEADER_WAVEFORM_URI as _,
+            );
+        }
+    }
+    // Whatever the cause, the utterance and a streaming session end with the request
+    (*(*recog_channel).audio_buffer).cancel();
     uni::inline_mrcp_engine_channel_message_send((*recog_channel).channel, message)
 }
 
+unsafe fn demo_recog_vendor_params_add(
+    message: *mut uni::mrcp_message_t,
+    params: &[(&str, String)],
+) {
+    let generic_header = uni::inline_mrcp_generic_header_prepare(message);
+    if generic_header.is_null() {
+        return;
+    }
+    let pool = (*message).pool;
+    if (*generic_header).vendor_specific_params.is_null() {
+        (*generic_header).vendor_specific_params = uni::apt_pair_array_create(params.len(), pool);
+    }
+    for (name, value) in params {
+        let mut name_str = std::mem::zeroed::<uni::apt_str_t>();
+        let mut value_str = std::mem::zeroed::<uni::apt_str_t>();
+        uni::inline_apt_string_assign_n(&mut name_str, name.as_ptr() as _, name.len(), pool);
+        uni::inline_apt_string_assign_n(&mut value_str, value.as_ptr() as _, value.len(), pool);
+        uni::apt_pair_array_append(
+            (*generic_header).vendor_specific_params,
+            &name_str,
+            &value_str,
+            pool,
+        );
+    }
+    uni::mrcp_generic_header_property_add(message, uni::GENERIC_HEADER_VENDOR_SPECIFIC_PARAMS as _);
+}
+
 pub unsafe extern "C" fn stream_write(
     stream: *mut uni::mpf_audio_stream_t,
     frame: *const uni::mpf_frame_t,
 ) -> uni::apt_bool_t {
     let demo_channel = (*stream).obj as *mut DemoRecogChannel;
-    if !(*demo_channel).stop_response.is_null() {
-        uni::inline_mrcp_engine_channel_message_send(
-            (*demo_channel).channel,
-            (*demo_channel).stop_response,
-        );
-        (*demo_channel).stop_response = std::ptr::null_mut() as _;
-        (*demo_channel).recog_request = std::ptr::null_mut() as _;
+    let stop_response = (*demo_channel)
+        .stop_response
+        .swap(std::ptr::null_mut(), Ordering::AcqRel);
+    if !stop_response.is_null() {
+        (*demo_channel)
+            .pending_request
+            .store(std::ptr::null_mut(), Ordering::Release);
+        (*demo_channel)
+            .recog_request
+            .store(std::ptr::null_mut(), Ordering::Release);
+        // A recording is cancelled by its own thread once it sees the request gone,
+        // the engine task joins that thread before it prepares the buffer again
+        if !(*demo_channel).waveform_input.load(Ordering::Acquire) {
+            (*(*demo_channel).audio_buffer).cancel();
+        }
+        uni::inline_mrcp_engine_channel_message_send((*demo_channel).channel, stop_response);
+        return uni::TRUE;
+    }
+    // The waveform thread has the buffer to itself till its recording is done
+    if (*demo_channel).waveform_input.load(Ordering::Acquire) {
         return uni::TRUE;
     }
-    if !(*demo_channel).recog_request.is_null() {
+    let request = (*demo_channel)
+        .pending_request
+        .swap(std::ptr::null_mut(), Ordering::AcqRel);
+    if !request.is_null() {
+        demo_recog_start(demo_channel, request);
+    }
+    if demo_recog_active(demo_channel) && !(*demo_channel).waveform_input.load(Ordering::Acquire) {
+        let audio = frame_audio(frame);
+        if ((*frame).type_ & (uni::MEDIA_FRAME_TYPE_AUDIO as i32))
+            == uni::MEDIA_FRAME_TYPE_AUDIO as i32
+        {
+            (*(*demo_channel).audio_buffer).tap(audio);
+        } else {
+            (*(*demo_channel).audio_buffer).tap(&[]);
+        }
         if ((*frame).type_ & (uni::MEDIA_FRAME_TYPE_EVENT as i32))
             == uni::MEDIA_FRAME_TYPE_EVENT as i32
         {
@@ -485,11 +857,11 @@ pub unsafe extern "C" fn stream_write(
                 )
             }
         } else {
-            let buf = std::slice::from_raw_parts(
-                (*frame).codec_frame.buffer as *mut u8,
-                (*frame).codec_frame.size,
-            );
-            (*(*demo_channel).audio_buffer).write(buf).ok();
+            if (*frame).type_ == uni::MEDIA_FRAME_TYPE_NONE as i32 || audio.is_empty() {
+                (*(*demo_channel).audio_buffer).dropout();
+            } else {
+                (*(*demo_channel).audio_buffer).write(audio).ok();
+            }
             let event = (*(*demo_channel).audio_buffer).detector_event();
             demo_recog_recognition_process(demo_channel, event);
         }
@@ -497,6 +869,15 @@ pub unsafe extern "C" fn stream_write(
     uni::TRUE
 }
 
+/// Codec payload of a frame, empty when it carries no buffer.
+unsafe fn frame_audio<'a>(frame: *const uni::mpf_frame_t) -> &'a [u8] {
+    let codec_frame = &(*frame).codec_frame;
+    if codec_frame.buffer.is_null() || codec_frame.size == 0 {
+        return &[];
+    }
+    std::slice::from_raw_parts(codec_frame.buffer as *const u8, codec_frame.size)
+}
+
 unsafe extern "C" fn demo_recog_msg_signal(
     type_: RecogMsgType,
     channel: *mut uni::mrcp_engine_channel_t,
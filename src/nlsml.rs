@@ -0,0 +1,136 @@
+use crate::backend::Alternative;
+
+/// Renders alternatives as an NLSML result (RFC 6787, section 9.6).
+/// Words go into nested `<input>` elements with `offset-start` and `offset-end`
+/// in ms from the start of the request's audio.
+pub fn render(alternatives: &[Alternative]) -> String {
+    let mut body = String::from("<?xml version=\"1.0\"?>\n<result>\n");
+    for alternative in alternatives {
+        body.push_str("  <interpretation");
+        if let Some(grammar) = alternative.grammar.as_ref() {
+            body.push_str(&format!(" grammar=\"{}\"", escape(grammar)));
+        }
+        body.push_str(&format!(" confidence=\"{:.2}\">\n", alternative.confidence));
+        let text = escape(&alternative.text);
+        body.push_str(&format!("    <instance>{}</instance>\n", text));
+        if alternative.words.is_empty() {
+            body.push_str(&format!("    <input mode=\"speech\">{}</input>\n", text));
+        } else {
+            body.push_str("    <input mode=\"speech\">\n");
+            for word in alternative.words.iter() {
+                body.push_str(&format!(
+                    "      <input mode=\"speech\" confidence=\"{:.2}\" offset-start=\"{}\" offset-end=\"{}\">{}</input>\n",
+                    word.confidence,
+                    word.start,
+                    word.end,
+                    escape(&word.text)
+                ));
+            }
+            body.push_str("    </input>\n");
+        }
+        body.push_str("  </interpretation>\n");
+    }
+    body.push_str("</result>\n");
+    body
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Word;
+
+    #[test]
+    fn escapes_markup_characters() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+        assert_eq!(escape("plain text"), "plain text");
+    }
+
+    #[test]
+    fn renders_text_without_words_as_input() {
+        let alternatives = [Alternative {
+            text: "fish & chips".to_owned(),
+            confidence: 0.5,
+            words: Vec::new(),
+            grammar: Some("session:menu<1>".to_owned()),
+        }];
+        assert_eq!(
+            render(&alternatives),
+            r#"<?xml version="1.0"?>
+<result>
+  <interpretation grammar="session:menu&lt;1&gt;" confidence="0.50">
+    <instance>fish &amp; chips</instance>
+    <input mode="speech">fish &amp; chips</input>
+  </interpretation>
+</result>
+"#
+        );
+    }
+
+    #[test]
+    fn renders_words_with_offsets() {
+        let word = |text: &str, start, end| Word {
+            text: text.to_owned(),
+            start,
+            end,
+            confidence: 0.875,
+        };
+        let alternatives = [
+            Alternative {
+                text: "yes <please>".to_owned(),
+                confidence: 0.9,
+                words: vec![word("yes", 120, 400), word("<please>", 400, 910)],
+                grammar: None,
+            },
+            Alternative {
+                text: "no".to_owned(),
+                confidence: 0.1,
+                words: Vec::new(),
+                grammar: None,
+            },
+        ];
+        assert_eq!(
+            render(&alternatives),
+            r#"<?xml version="1.0"?>
+<result>
+  <interpretation confidence="0.90">
+    <instance>yes &lt;please&gt;</instance>
+    <input mode="speech">
+      <input mode="speech" confidence="0.88" offset-start="120" offset-end="400">yes</input>
+      <input mode="speech" confidence="0.88" offset-start="400" offset-end="910">&lt;please&gt;</input>
+    </input>
+  </interpretation>
+  <interpretation confidence="0.10">
+    <instance>no</instance>
+    <input mode="speech">no</input>
+  </interpretation>
+</result>
+"#
+        );
+    }
+
+    #[test]
+    fn renders_empty_result() {
+        assert_eq!(
+            render(&[]),
+            "<?xml version=\"1.0\"?>\n<result>\n</result>\n"
+        );
+    }
+}
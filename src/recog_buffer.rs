@@ -1,77 +1,1308 @@
-use std::io::Write;
+use std::{
+    collections::VecDeque,
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, TryRecvError},
+        Arc,
+    },
+    thread,
+    time::SystemTime,
+};
 
-use crate::{speech_detector::SpeechDetectorEvent, uni};
+use crate::{
+    audio_quality::AudioQuality,
+    backend::{Backend, RecognitionParams, RecognitionResult, StreamingRecognition},
+    config::{EngineConfig, TimeoutAction},
+    debug_tap::DebugTap,
+    dsp::DspChain,
+    grammar::{GrammarMatch, Grammars},
+    nlsml,
+    recog_params::{self, RecogParams},
+    resampler::Resampler,
+    speech_detector::{self, SpeechDetector, SpeechDetectorEvent, DEFAULT_SENSITIVITY},
+    uni,
+    utterance::Utterance,
+    waveform_writer::WaveformWriter,
+};
+
+const DEFAULT_SAMPLE_RATE: usize = 8000;
+
+/// What came of a recognized utterance.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecognitionOutcome {
+    /// NLSML result.
+    Success(String),
+    NoMatch,
+    Error,
+    /// No hotword in the utterance, listening goes on.
+    KeepListening,
+}
+
+/// A RECOGNIZE as read on the engine task, waiting for the media thread to start it.
+struct Prepared {
+    sample_rate: usize,
+    params: RecogParams,
+    waveform_name: Option<String>,
+    input_waveform_uri: Option<String>,
+    recognition_params: RecognitionParams,
+}
+
+#[derive(Debug)]
+struct Pause {
+    speech: usize,
+    silence: usize,
+}
+
+/// Audio preceding the detected onset: the pre-roll itself
+/// plus the frames the detector needed to confirm speech.
+struct PreRoll {
+    samples: VecDeque<i16>,
+    capacity: usize,
+}
+
+impl PreRoll {
+    fn new(config: &EngineConfig, sample_rate: usize) -> Self {
+        let duration = config.audio_preroll + config.vad_speech_timeout * 3 / 2;
+        let capacity = duration * sample_rate / 1000;
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn push(&mut self, samples: &[i16]) {
+        self.samples.extend(samples);
+        let excess = self.samples.len().saturating_sub(self.capacity);
+        self.samples.drain(..excess);
+    }
+
+    fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    fn drain_into(&mut self, utterance: &mut Vec<i16>) {
+        utterance.extend(self.samples.drain(..));
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
 
 pub struct RecogBuffer {
-    count: usize,
+    input_rate: usize,
+    sample_rate: usize,
+    input: Vec<i16>,
+    resampler: Option<Resampler>,
+    dsp: DspChain,
+    tap: Option<DebugTap>,
+    tap_samples: Vec<i16>,
+    position: usize,
+    frame_duration: usize,
+    /// Sub-millisecond remainder of the audio written so far, in ms * input rate.
+    frame_remainder: usize,
     speech_event: SpeechDetectorEvent,
+    detector: Box<dyn SpeechDetector>,
+    samples: Vec<i16>,
+    preroll: PreRoll,
+    utterance: Utterance,
+    max_utterance_samples: usize,
+    recording: bool,
+    recognized: Option<Arc<Utterance>>,
+    backend: Backend,
+    recognition_params: RecognitionParams,
+    stream: Option<StreamingRecognition>,
+    pending: Option<Receiver<Result<RecognitionResult, String>>>,
+    /// Latest partial result and how it matches the grammars, matched once as it arrives.
+    partial: Option<(String, GrammarMatch)>,
+    quality: Option<AudioQuality>,
+    input_started: bool,
+    input_ended: bool,
+    /// Set by START-INPUT-TIMERS on the engine task, read on the media thread.
+    timers_started: AtomicBool,
+    no_input_timeout: usize,
+    no_input_duration: usize,
+    speech_complete_timeout: usize,
+    speech_incomplete_timeout: usize,
+    recognition_timeout: usize,
+    recognition_duration: usize,
+    hotword: bool,
+    hotword_min_duration: usize,
+    hotword_max_duration: Option<usize>,
+    waveform_name: Option<String>,
+    input_waveform_uri: Option<String>,
+    speech_duration: usize,
+    pause: Option<Pause>,
+    grammars: Grammars,
+    config: EngineConfig,
+    defaults: RecogParams,
+    prepared: Option<Prepared>,
+    waveform_writer: Option<WaveformWriter>,
 }
 
 impl RecogBuffer {
-    pub fn leaked() -> *mut Self {
-        Box::into_raw(Box::new(Self {
-            count: 0,
+    pub fn leaked(config: &EngineConfig, backend: Backend) -> *mut Self {
+        let mut buffer = Box::new(Self {
+            input_rate: DEFAULT_SAMPLE_RATE,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            input: Vec::new(),
+            resampler: None,
+            dsp: DspChain::new(config, DEFAULT_SAMPLE_RATE),
+            tap: None,
+            tap_samples: Vec::new(),
+            position: 0,
+            frame_duration: 0,
+            frame_remainder: 0,
             speech_event: SpeechDetectorEvent::None,
-        }))
+            detector: speech_detector::create(config),
+            samples: Vec::new(),
+            preroll: PreRoll::new(config, DEFAULT_SAMPLE_RATE),
+            utterance: Utterance::new(DEFAULT_SAMPLE_RATE),
+            max_utterance_samples: config.max_utterance_duration * DEFAULT_SAMPLE_RATE / 1000,
+            recording: false,
+            recognized: None,
+            backend,
+            recognition_params: RecognitionParams::default(),
+            stream: None,
+            pending: None,
+            partial: None,
+            quality: None,
+            input_started: false,
+            input_ended: false,
+            timers_started: AtomicBool::new(true),
+            no_input_timeout: config.no_input_timeout,
+            no_input_duration: 0,
+            speech_complete_timeout: config.speech_complete_timeout,
+            speech_incomplete_timeout: config.speech_incomplete_timeout,
+            recognition_timeout: config.recognition_timeout,
+            recognition_duration: 0,
+            hotword: false,
+            hotword_min_duration: 0,
+            hotword_max_duration: None,
+            waveform_name: None,
+            input_waveform_uri: None,
+            speech_duration: 0,
+            pause: None,
+            grammars: Grammars::default(),
+            config: config.clone(),
+            defaults: RecogParams::default(),
+            prepared: None,
+            waveform_writer: None,
+        });
+        buffer.reallocate();
+        Box::into_raw(buffer)
     }
 
     pub unsafe fn destroy(this: *mut Self) {
         drop(Box::from_raw(this));
     }
 
-    pub fn prepare(&self, _request: *mut uni::mrcp_message_t) {}
+    /// Applies channel attribs on top of the engine config.
+    pub fn configure(&mut self, attribs: &[(String, String)]) {
+        if attribs.is_empty() {
+            return;
+        }
+        for (name, value) in attribs {
+            self.config.set(name, value);
+        }
+        log::info!("Channel config: {:?}", self.config);
+        self.detector = speech_detector::create(&self.config);
+        self.reallocate();
+    }
+
+    /// Switches to the sample rate of the negotiated codec.
+    pub fn set_sample_rate(&mut self, sample_rate: usize) {
+        if sample_rate == 0 || sample_rate == self.input_rate {
+            return;
+        }
+        log::info!("Codec sample rate {} Hz", sample_rate);
+        self.input_rate = sample_rate;
+        self.reallocate();
+    }
+
+    /// Audio is processed at `recognizer-sample-rate`, resampled from the codec rate if needed.
+    fn reallocate(&mut self) {
+        self.sample_rate = match self.config.recognizer_sample_rate {
+            0 => self.input_rate,
+            rate => rate,
+        };
+        self.resampler = if self.sample_rate == self.input_rate {
+            None
+        } else {
+            Some(Resampler::new(
+                self.input_rate,
+                self.sample_rate,
+                self.config.resample_quality,
+            ))
+        };
+        self.dsp = DspChain::new(&self.config, self.sample_rate);
+        self.preroll = PreRoll::new(&self.config, self.sample_rate);
+        self.utterance = Utterance::new(self.sample_rate);
+        self.max_utterance_samples = self.config.max_utterance_duration * self.sample_rate / 1000;
+    }
+
+    /// Starts recording the channel audio when `debug-tap` is on.
+    pub fn open_debug_tap(&mut self, name: &str, sample_rate: usize) {
+        if !self.config.debug_tap || self.tap.is_some() {
+            return;
+        }
+        match DebugTap::create(&self.config.debug_tap_dir, name, sample_rate) {
+            Ok(tap) => self.tap = Some(tap),
+            Err(e) => log::error!("Unable to open debug tap {:?}: {}", name, e),
+        }
+    }
+
+    pub fn close_debug_tap(&mut self) {
+        self.tap = None;
+    }
+
+    /// The debug tap belongs to the media thread, so it is left alone while
+    /// an Input-Waveform-URI recording is written from its own thread.
+    fn media_tap(&mut self) -> Option<&mut DebugTap> {
+        if self.input_waveform_uri.is_some() {
+            return None;
+        }
+        self.tap.as_mut()
+    }
+
+    /// Feeds the debug tap with a frame as received while recognition is running.
+    /// A frame without audio goes in as silence.
+    pub fn tap(&mut self, buf: &[u8]) {
+        let Some(tap) = self.tap.as_mut() else {
+            return;
+        };
+        if buf.is_empty() {
+            tap.silence();
+            return;
+        }
+        self.tap_samples.clear();
+        self.tap_samples.extend(
+            buf.chunks_exact(2)
+                .map(|pair| i16::from_ne_bytes([pair[0], pair[1]])),
+        );
+        tap.audio(&self.tap_samples);
+    }
+
+    pub unsafe fn set_params(&mut self, request: *mut uni::mrcp_message_t) {
+        self.defaults.update(request);
+        log::debug!("Channel defaults: {:?}", self.defaults);
+    }
+
+    /// Reads a RECOGNIZE on the engine task. Nothing the media thread works with
+    /// is touched here, `start` applies the request on the media thread.
+    pub unsafe fn prepare(&mut self, request: *mut uni::mrcp_message_t, sample_rate: usize) {
+        let params = self.defaults.merged(request);
+        let timers_started = params.start_input_timers.unwrap_or(true);
+        self.timers_started.store(timers_started, Ordering::Release);
+        if !timers_started {
+            log::debug!("No-Input-Timeout is deferred till START-INPUT-TIMERS");
+        }
+        let waveform_name = if params.save_waveform.unwrap_or(false) {
+            Some(self.waveform_file_name(request))
+        } else {
+            None
+        };
+        self.grammars.activate(
+            &recog_params::content_type(request),
+            &recog_params::content_id(request),
+            &recog_params::apt_string(&(*request).body),
+        );
+        let recognition_params = RecognitionParams {
+            language: params
+                .speech_language
+                .clone()
+                .unwrap_or_else(|| self.config.language.clone()),
+            grammars: self.grammars.ids(),
+            phrases: self.grammars.phrases(),
+            n_best: params
+                .n_best_list_length
+                .unwrap_or(self.config.n_best_list_length),
+            confidence_threshold: params
+                .confidence_threshold
+                .unwrap_or(self.config.confidence_threshold),
+        };
+        self.prepared = Some(Prepared {
+            sample_rate,
+            params,
+            waveform_name,
+            input_waveform_uri: recog_params::input_waveform_uri(request),
+            recognition_params,
+        });
+    }
+
+    /// Starts listening for the prepared RECOGNIZE, on the thread that writes the audio.
+    pub fn start(&mut self) {
+        let Some(prepared) = self.prepared.take() else {
+            return;
+        };
+        let params = prepared.params;
+        self.set_sample_rate(prepared.sample_rate);
+        self.detector
+            .set_sensitivity(params.sensitivity_level.unwrap_or(DEFAULT_SENSITIVITY));
+        self.no_input_timeout = params
+            .no_input_timeout
+            .unwrap_or(self.config.no_input_timeout);
+        self.no_input_duration = 0;
+        self.speech_complete_timeout = params
+            .speech_complete_timeout
+            .unwrap_or(self.config.speech_complete_timeout);
+        self.speech_incomplete_timeout = params
+            .speech_incomplete_timeout
+            .unwrap_or(self.config.speech_incomplete_timeout);
+        self.recognition_timeout = params
+            .recognition_timeout
+            .unwrap_or(self.config.recognition_timeout);
+        self.recognition_duration = 0;
+        self.hotword = params.hotword.unwrap_or(false);
+        self.hotword_min_duration = params.hotword_min_duration.unwrap_or(0);
+        self.hotword_max_duration = params.hotword_max_duration.filter(|&max| max > 0);
+        if self.hotword {
+            log::info!(
+                "Hotword mode, utterances from {} to {:?} ms",
+                self.hotword_min_duration,
+                self.hotword_max_duration
+            );
+        }
+        self.waveform_name = prepared.waveform_name;
+        self.input_waveform_uri = prepared.input_waveform_uri;
+        self.recognition_params = prepared.recognition_params;
+        self.input_ended = false;
+        self.position = 0;
+        self.preroll.clear();
+        self.restart_writing();
+        if let Some(tap) = self.media_tap() {
+            tap.note("RECOGNIZE");
+        }
+    }
+
+    pub unsafe fn define_grammar(&mut self, request: *mut uni::mrcp_message_t) {
+        self.grammars.define(
+            &recog_params::content_id(request),
+            &recog_params::content_type(request),
+            &recog_params::apt_string(&(*request).body),
+        );
+    }
 
     pub fn detector_event(&self) -> SpeechDetectorEvent {
         self.speech_event
     }
 
-    pub fn start_input_timers(&mut self) {}
+    pub fn start_input_timers(&self) {
+        log::debug!("Start input timers");
+        self.timers_started.store(true, Ordering::Release);
+    }
+
+    pub fn hotword(&self) -> bool {
+        self.hotword
+    }
+
+    pub fn input_waveform_uri(&self) -> Option<&str> {
+        self.input_waveform_uri.as_deref()
+    }
+
+    /// Ends input that does not come from RTP: speech so far is recognized, no speech is no input.
+    /// A hotword miss at the end of input is no match, as nothing more is coming.
+    pub fn end_of_input(&mut self) {
+        self.input_ended = true;
+        if self.recording {
+            log::debug!("End of input in speech");
+            self.recognize(self.utterance.duration());
+        } else {
+            log::debug!("End of input without speech");
+            self.speech_event = SpeechDetectorEvent::Noinput;
+        }
+    }
 
     pub fn input_started(&self) -> bool {
-        false
+        self.input_started
     }
 
-    pub fn start_input(&mut self) {}
+    pub fn start_input(&mut self) {
+        self.input_started = true;
+    }
 
     pub fn recognize(&mut self, duration: usize) {
-        log::info!("Recognizing {} ms", duration);
+        let mut utterance =
+            std::mem::replace(&mut self.utterance, Utterance::new(self.sample_rate));
+        utterance.end = utterance.start + utterance.samples.len();
+        log::info!(
+            "Recognizing {} ms of speech, utterance {} ms at samples {}..{}",
+            duration,
+            utterance.duration(),
+            utterance.start,
+            utterance.end
+        );
+        let quality = AudioQuality::measure(&utterance, self.detector.noise_floor());
+        log::info!("Audio quality: {}", quality);
+        self.quality = Some(quality);
+        self.recording = false;
+        let utterance = Arc::new(utterance);
+        self.pending = match self.stream.take() {
+            Some(stream) => Some(stream.finish()),
+            None => self.spawn_recognition(utterance.clone()),
+        };
+        self.recognized = Some(utterance);
         self.speech_event = SpeechDetectorEvent::Recognizing;
     }
 
+    fn spawn_recognition(
+        &self,
+        utterance: Arc<Utterance>,
+    ) -> Option<Receiver<Result<RecognitionResult, String>>> {
+        let (sender, receiver) = mpsc::channel();
+        let backend = match &self.backend {
+            Backend::Batch(backend) => backend.clone(),
+            Backend::Streaming(_) => {
+                log::error!("Streaming recognition was not started");
+                return None;
+            }
+        };
+        let params = self.recognition_params.clone();
+        let spawned = thread::Builder::new()
+            .name("recognizer".to_owned())
+            .spawn(move || {
+                sender.send(backend.recognize(&utterance, &params)).ok();
+            });
+        match spawned {
+            Ok(_) => Some(receiver),
+            Err(e) => {
+                log::error!("Unable to start recognition: {}", e);
+                None
+            }
+        }
+    }
+
     pub fn duration_timeout(&self) -> usize {
-        20000
+        self.recognition_duration
     }
 
-    pub fn load_result(&self) -> Option<String> {
-        log::info!("Result count: {}", self.count);
-        if self.detector_event() == SpeechDetectorEvent::Recognizing {
-            Some("Привет, мир!".to_owned())
-        } else {
-            None
+    pub fn recognize_on_timeout(&self) -> bool {
+        self.config.recognition_timeout_action == TimeoutAction::Recognize
+    }
+
+    /// Polls the recognition started on end of speech, `None` while it is still running.
+    pub fn load_result(&mut self) -> Option<RecognitionOutcome> {
+        if self.detector_event() != SpeechDetectorEvent::Recognizing {
+            return None;
+        }
+        let Some(pending) = self.pending.as_ref() else {
+            return Some(RecognitionOutcome::Error);
+        };
+        let result = match pending.try_recv() {
+            Ok(result) => result,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err("recognizer gave no result".to_owned()),
+        };
+        self.pending = None;
+        match result {
+            Ok(result) => Some(self.outcome(result)),
+            Err(e) => {
+                log::error!("Recognition failed: {}", e);
+                Some(RecognitionOutcome::Error)
+            }
         }
     }
 
+    /// Keeps alternatives above Confidence-Threshold that match the active grammars, up to N-Best-List-Length.
+    /// Only a complete match counts, a prefix of a grammar path is no match.
+    fn outcome(&self, result: RecognitionResult) -> RecognitionOutcome {
+        log::info!("Recognized {:?}", result.alternatives);
+        let offset = self.recognized.as_ref().map_or(0, |utterance| {
+            utterance.start * 1000 / utterance.sample_rate.max(1)
+        });
+        let alternatives: Vec<_> = result
+            .alternatives
+            .into_iter()
+            .filter(|alternative| {
+                alternative.confidence >= self.recognition_params.confidence_threshold
+            })
+            .filter(|alternative| {
+                self.grammars.matches(&alternative.text) == GrammarMatch::Complete
+            })
+            .take(self.recognition_params.n_best.max(1))
+            .map(|mut alternative| {
+                alternative.grammar = self.grammars.matched(&alternative.text).map(str::to_owned);
+                // Backends time words from the utterance start, results from the request start
+                for word in alternative.words.iter_mut() {
+                    word.start += offset;
+                    word.end += offset;
+                }
+                alternative
+            })
+            .collect();
+        if alternatives.is_empty() {
+            if self.hotword && !self.input_ended {
+                log::info!("No hotword, keep listening");
+                return RecognitionOutcome::KeepListening;
+            }
+            return RecognitionOutcome::NoMatch;
+        }
+        RecognitionOutcome::Success(nlsml::render(&alternatives))
+    }
+
+    /// Listens afresh within the same request, the request's timers and
+    /// the position utterance offsets are counted from keep running.
     pub fn restart_writing(&mut self) {
-        self.count = 0;
+        self.frame_remainder = 0;
+        self.input_started = false;
+        self.speech_event = SpeechDetectorEvent::None;
+        self.discard_utterance();
+        self.detector.reset();
+    }
+
+    /// Drops what a stopped request left running: the streaming session and a pending recognition.
+    pub fn cancel(&mut self) {
+        log::debug!("Cancel recognition");
+        self.speech_event = SpeechDetectorEvent::None;
+        self.preroll.clear();
+        self.discard_utterance();
+    }
+
+    fn discard_utterance(&mut self) {
+        self.speech_duration = 0;
+        self.pause = None;
+        self.utterance.samples.clear();
+        self.utterance.dropouts = 0;
+        self.utterance.dropout_duration = 0;
+        self.recording = false;
+        self.recognized = None;
+        self.stream = None;
+        self.pending = None;
+        self.partial = None;
+        self.quality = None;
+    }
+
+    /// Accounts a frame that arrived empty or without audio. It takes the time of
+    /// the last audio frame, which goes by as silence for the endpoint and the timers.
+    pub fn dropout(&mut self) {
+        if let Some(tap) = self.media_tap() {
+            tap.note("dropout");
+        }
+        if self.detector_event() == SpeechDetectorEvent::Recognizing {
+            return;
+        }
+        if self.frame_duration == 0 {
+            self.frame_duration = uni::CODEC_FRAME_TIME_BASE as usize;
+        }
+        if self.recording {
+            self.utterance.dropouts += 1;
+            self.utterance.dropout_duration += self.frame_duration;
+        }
+        self.position += self.frame_duration * self.sample_rate / 1000;
+        self.speech_event = self.endpoint(SpeechDetectorEvent::None);
+        if self.speech_event == SpeechDetectorEvent::None {
+            self.count_timers();
+        }
+    }
+
+    /// Quality of the recognized utterance when `audio-quality-params` is on.
+    pub fn audio_quality_params(&self) -> Option<AudioQuality> {
+        self.quality.filter(|_| self.config.audio_quality_params)
+    }
+
+    /// Expands `{channel}`, `{request}` and `{time}` in the configured `waveform-name`.
+    unsafe fn waveform_file_name(&self, request: *const uni::mrcp_message_t) -> String {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or_default();
+        self.config
+            .waveform_name
+            .replace(
+                "{channel}",
+                &format!(
+                    "{}@{}",
+                    recog_params::apt_string(&(*request).channel_id.session_id),
+                    recog_params::apt_string(&(*request).channel_id.resource_name)
+                ),
+            )
+            .replace("{request}", &(*request).start_line.request_id.to_string())
+            .replace("{time}", &time.to_string())
+    }
+
+    /// Saves the last utterance when `Save-Waveform` is on and returns the `Waveform-URI` value.
+    /// The file is written by the waveform writer thread, there is nothing to save without speech.
+    pub fn save_waveform(&mut self) -> Option<String> {
+        let name = self.waveform_name.as_ref()?;
+        let utterance = match &self.recognized {
+            Some(recognized) => recognized.clone(),
+            None => Arc::new(self.utterance.clone()),
+        };
+        if utterance.samples.is_empty() {
+            return None;
+        }
+        let path = Path::new(&self.config.waveform_dir).join(name);
+        if self.waveform_writer.is_none() {
+            match WaveformWriter::spawn() {
+                Ok(writer) => self.waveform_writer = Some(writer),
+                Err(e) => {
+                    log::error!("Unable to start the waveform writer: {}", e);
+                    return None;
+                }
+            }
+        }
+        let writer = self.waveform_writer.as_ref()?;
+        let uri = format!("<file://{}>", path.display());
+        let size = writer.save(path, utterance.clone());
+        Some(format!(
+            "{};size={};duration={}",
+            uri,
+            size,
+            utterance.duration()
+        ))
+    }
+
+    /// Adds the frame to the utterance, true once it reaches `max-utterance-duration`.
+    fn record(&mut self) -> bool {
+        let room = self
+            .max_utterance_samples
+            .saturating_sub(self.utterance.samples.len());
+        let samples = &self.samples[..room.min(self.samples.len())];
+        self.utterance.samples.extend_from_slice(samples);
+        if let Some(stream) = self.stream.as_ref() {
+            stream.feed(samples);
+        }
+        self.utterance.samples.len() >= self.max_utterance_samples
+    }
+
+    /// Starts a streaming backend on the speech onset with the audio recorded so far.
+    fn start_stream(&mut self) {
+        let Backend::Streaming(backend) = &self.backend else {
+            return;
+        };
+        match StreamingRecognition::start(
+            backend.clone(),
+            self.recognition_params.clone(),
+            self.sample_rate,
+        ) {
+            Ok(stream) => {
+                stream.feed(&self.utterance.samples);
+                self.stream = Some(stream);
+            }
+            Err(e) => log::error!("Unable to start streaming recognition: {}", e),
+        }
+    }
+
+    /// Takes the latest partial result of a streaming backend.
+    fn update_partial(&mut self) {
+        let Some(partial) = self.stream.as_ref().and_then(StreamingRecognition::partial) else {
+            return;
+        };
+        log::debug!("Partial result {:?}", partial);
+        if let Some(tap) = self.media_tap() {
+            tap.note(&format!("partial {}", partial));
+        }
+        self.set_partial(partial);
+    }
+
+    fn set_partial(&mut self, partial: String) {
+        let grammar_match = self.grammars.matches(&partial);
+        self.partial = Some((partial, grammar_match));
+    }
+
+    /// A streaming session that failed, to start or later, ends the recognition
+    /// with its error right away instead of at the endpoint.
+    fn check_stream(&mut self) {
+        let Some(error) = self.stream.as_ref().and_then(StreamingRecognition::error) else {
+            return;
+        };
+        log::error!("Streaming recognition failed: {}", error);
+        self.stream = None;
+        self.recording = false;
+        let (sender, receiver) = mpsc::channel();
+        sender.send(Err(error)).ok();
+        self.pending = Some(receiver);
+        self.speech_event = SpeechDetectorEvent::Recognizing;
+    }
+
+    fn hotword_duration_fits(&self, duration: usize) -> bool {
+        duration >= self.hotword_min_duration
+            && !matches!(self.hotword_max_duration, Some(max) if duration > max)
+    }
+
+    /// Speech is complete once the hypothesis matches a grammar. Without a hypothesis,
+    /// as with batch backends that give no partial results, it cannot be told complete
+    /// and Speech-Incomplete-Timeout applies.
+    fn endpoint_timeout(&self) -> usize {
+        match self.partial {
+            Some((_, GrammarMatch::Complete)) => self.speech_complete_timeout,
+            Some(_) | None => self.speech_incomplete_timeout,
+        }
+    }
+
+    /// Holds the detector's end of speech until the pause reaches
+    /// Speech-Complete-Timeout or Speech-Incomplete-Timeout.
+    fn endpoint(&mut self, event: SpeechDetectorEvent) -> SpeechDetectorEvent {
+        match event {
+            SpeechDetectorEvent::Inactivity { duration } => {
+                self.pause = Some(Pause {
+                    speech: self.speech_duration + duration,
+                    silence: self.config.vad_hangover,
+                });
+            }
+            SpeechDetectorEvent::Activity => {
+                if let Some(pause) = self.pause.take() {
+                    self.speech_duration = pause.speech + pause.silence;
+                }
+                return event;
+            }
+            _ => {}
+        }
+        let timeout = self.endpoint_timeout();
+        match self.pause.as_mut() {
+            None => event,
+            Some(pause) => {
+                if event == SpeechDetectorEvent::None {
+                    pause.silence += self.frame_duration;
+                }
+                if pause.silence < timeout {
+                    return SpeechDetectorEvent::None;
+                }
+                log::info!(
+                    "End of speech after {} ms of silence, noise floor {:.1} dBFS",
+                    pause.silence,
+                    self.detector.noise_floor()
+                );
+                let duration = pause.speech;
+                self.end_of_speech(duration)
+            }
+        }
+    }
+
+    /// Ends the speech in progress, a hotword utterance out of bounds is dropped.
+    fn end_of_speech(&mut self, duration: usize) -> SpeechDetectorEvent {
+        self.pause = None;
+        self.speech_duration = 0;
+        if self.hotword && !self.hotword_duration_fits(duration) {
+            log::info!("Ignoring {} ms utterance in hotword mode", duration);
+            self.restart_writing();
+            return SpeechDetectorEvent::None;
+        }
+        SpeechDetectorEvent::Inactivity { duration }
+    }
+
+    /// Recognition-Timeout once input started, No-Input-Timeout before.
+    fn count_timers(&mut self) {
+        if self.input_started {
+            self.recognition_duration += self.frame_duration;
+            if self.recognition_duration >= self.recognition_timeout {
+                log::debug!("Recognition timeout {} ms", self.recognition_timeout);
+                self.speech_event = SpeechDetectorEvent::DurationTimeout;
+            }
+        } else if self.timers_started.load(Ordering::Acquire) {
+            self.no_input_duration += self.frame_duration;
+            if self.no_input_duration >= self.no_input_timeout {
+                log::debug!("No input within {} ms", self.no_input_timeout);
+                self.speech_event = SpeechDetectorEvent::Noinput;
+            }
+        }
+    }
+
+    /// Decodes, resamples and filters a frame into `samples`, advancing the position.
+    fn read_frame(&mut self, buf: &[u8]) {
+        let input = match self.resampler {
+            Some(_) => &mut self.input,
+            None => &mut self.samples,
+        };
+        input.clear();
+        input.extend(
+            buf.chunks_exact(2)
+                .map(|pair| i16::from_ne_bytes([pair[0], pair[1]])),
+        );
+        // Frame size follows the negotiated ptime, so time is counted in samples
+        self.frame_remainder += input.len() * 1000;
+        self.frame_duration = self.frame_remainder / self.input_rate;
+        self.frame_remainder %= self.input_rate;
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.process(&self.input, &mut self.samples);
+        }
+        self.dsp.process(&mut self.samples);
+        self.position += self.samples.len();
     }
 }
 
 impl Write for RecogBuffer {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         if self.detector_event() == SpeechDetectorEvent::Recognizing {
-            return Ok(0);
+            // A hotword may be spoken while the last utterance is recognized
+            if !self.hotword {
+                return Ok(0);
+            }
+            self.read_frame(buf);
+            self.preroll.push(&self.samples);
+            return Ok(buf.len());
+        }
+        log::debug!("WRITE: {} samples at {}", buf.len() / 2, self.position);
+        self.read_frame(buf);
+        let full = if self.recording {
+            self.record()
+        } else {
+            self.preroll.push(&self.samples);
+            false
+        };
+        let event = self.detector.process(&self.samples, self.frame_duration);
+        if event == SpeechDetectorEvent::Activity && !self.recording {
+            log::info!(
+                "Start of speech, noise floor {:.1} dBFS",
+                self.detector.noise_floor()
+            );
+            self.utterance.start = self.position - self.preroll.len();
+            self.preroll.drain_into(&mut self.utterance.samples);
+            self.recording = true;
+            self.start_stream();
         }
-        self.count += 1;
-        log::debug!("WRITE: {} frames", self.count);
-        if self.count < 1100 {
-            Ok(buf.len())
+        self.update_partial();
+        self.speech_event = if full {
+            log::warn!(
+                "Utterance reaches {} ms, ending speech",
+                self.config.max_utterance_duration
+            );
+            self.end_of_speech(self.utterance.duration())
         } else {
-            self.speech_event = SpeechDetectorEvent::DurationTimeout;
-            Ok(0)
+            self.endpoint(event)
+        };
+        self.check_stream();
+        let speech_event = self.speech_event;
+        if let Some(tap) = self.media_tap() {
+            tap.detector_event(event);
+            if speech_event != event {
+                tap.detector_event(speech_event);
+            }
         }
+        if self.speech_event == SpeechDetectorEvent::None {
+            self.count_timers();
+        }
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::backend::{
+        Alternative, DemoBackend, RecognizerBackend, StreamingBackend, StreamingSession,
+    };
+
+    const DIGITS_GRAMMAR: &str = r#"<grammar root="main"><rule id="main">
+        <one-of><item>один два три</item><item>четыре</item></one-of>
+    </rule></grammar>"#;
+
+    fn buffer() -> Box<RecogBuffer> {
+        let config = EngineConfig::default();
+        unsafe {
+            Box::from_raw(RecogBuffer::leaked(
+                &config,
+                Backend::Batch(Arc::new(DemoBackend)),
+            ))
+        }
+    }
+
+    fn result(text: &str) -> RecognitionResult {
+        RecognitionResult {
+            alternatives: vec![Alternative {
+                text: text.to_owned(),
+                confidence: 0.9,
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[test]
+    fn outcome_requires_complete_match() {
+        let mut buffer = buffer();
+        buffer
+            .grammars
+            .activate("application/srgs+xml", "digits", DIGITS_GRAMMAR);
+        let RecognitionOutcome::Success(nlsml) = buffer.outcome(result("один два три"))
+        else {
+            panic!("no match");
+        };
+        assert!(nlsml.contains(r#"grammar="session:digits""#), "{}", nlsml);
+        assert_eq!(
+            buffer.outcome(result("один два")),
+            RecognitionOutcome::NoMatch
+        );
+        assert_eq!(buffer.outcome(result("пять")), RecognitionOutcome::NoMatch);
+    }
+
+    #[test]
+    fn outcome_times_words_from_request_start() {
+        let mut buffer = buffer();
+        buffer.recognized = Some(Arc::new(Utterance {
+            start: DEFAULT_SAMPLE_RATE,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            ..Default::default()
+        }));
+        let result = DemoBackend
+            .recognize(
+                &Utterance {
+                    samples: vec![0; DEFAULT_SAMPLE_RATE],
+                    sample_rate: DEFAULT_SAMPLE_RATE,
+                    ..Default::default()
+                },
+                &RecognitionParams::default(),
+            )
+            .unwrap();
+        let RecognitionOutcome::Success(nlsml) = buffer.outcome(result) else {
+            panic!("no match");
+        };
+        assert!(
+            nlsml.contains(r#"offset-start="1000" offset-end="1500""#),
+            "{}",
+            nlsml
+        );
+    }
+
+    #[test]
+    fn outcome_without_grammars_accepts_any_text() {
+        let buffer = buffer();
+        assert!(matches!(
+            buffer.outcome(result("что угодно")),
+            RecognitionOutcome::Success(_)
+        ));
+        assert_eq!(buffer.outcome(result("")), RecognitionOutcome::NoMatch);
+    }
+
+    #[test]
+    fn outcome_in_hotword_mode_keeps_listening() {
+        let mut buffer = buffer();
+        buffer.hotword = true;
+        buffer
+            .grammars
+            .activate("application/srgs+xml", "digits", DIGITS_GRAMMAR);
+        assert_eq!(
+            buffer.outcome(result("один два")),
+            RecognitionOutcome::KeepListening
+        );
+    }
+
+    #[test]
+    fn endpoint_waits_incomplete_timeout_until_hypothesis_matches() {
+        let mut buffer = buffer();
+        buffer.speech_complete_timeout = 300;
+        buffer.speech_incomplete_timeout = 1500;
+        buffer
+            .grammars
+            .activate("application/srgs+xml", "digits", DIGITS_GRAMMAR);
+        assert_eq!(buffer.endpoint_timeout(), 1500);
+        buffer.set_partial("один два".to_owned());
+        assert_eq!(buffer.endpoint_timeout(), 1500);
+        buffer.set_partial("один два три".to_owned());
+        assert_eq!(buffer.endpoint_timeout(), 300);
+    }
+
+    fn preroll(duration: usize) -> PreRoll {
+        let config = EngineConfig {
+            audio_preroll: duration,
+            vad_speech_timeout: 0,
+            ..Default::default()
+        };
+        PreRoll::new(&config, DEFAULT_SAMPLE_RATE)
+    }
+
+    #[test]
+    fn preroll_keeps_the_latest_samples_in_order() {
+        let mut preroll = preroll(100);
+        let samples: Vec<i16> = (0..2000).collect();
+        for frame in samples.chunks(160) {
+            preroll.push(frame);
+        }
+        assert_eq!(preroll.len(), 800);
+        let mut drained = Vec::new();
+        preroll.drain_into(&mut drained);
+        assert_eq!(drained, &samples[1200..]);
+        assert_eq!(preroll.len(), 0);
+    }
+
+    #[test]
+    fn preroll_truncates_a_push_over_capacity() {
+        let mut preroll = preroll(20);
+        let samples: Vec<i16> = (0..1000).collect();
+        preroll.push(&samples);
+        let mut drained = Vec::new();
+        preroll.drain_into(&mut drained);
+        assert_eq!(drained, &samples[840..]);
+    }
+
+    #[test]
+    fn preroll_drains_after_the_utterance_so_far() {
+        let mut preroll = preroll(100);
+        preroll.push(&[1, 2, 3]);
+        preroll.push(&[4, 5]);
+        let mut utterance = vec![-1, -2];
+        preroll.drain_into(&mut utterance);
+        assert_eq!(utterance, [-1, -2, 1, 2, 3, 4, 5]);
+        preroll.push(&[6]);
+        preroll.clear();
+        assert_eq!(preroll.len(), 0);
+    }
+
+    fn write_silence(buffer: &mut RecogBuffer, duration: usize) {
+        let frame = vec![0u8; DEFAULT_SAMPLE_RATE / 50 * 2];
+        for _ in 0..duration / 20 {
+            buffer.write_all(&frame).unwrap();
+        }
+    }
+
+    /// Writes 20 ms frames of a loud square wave, starting input on Activity as the
+    /// channel does, and returns the events with the time they came at.
+    fn write_speech(
+        buffer: &mut RecogBuffer,
+        duration: usize,
+    ) -> Vec<(usize, SpeechDetectorEvent)> {
+        let frame: Vec<u8> = (0..DEFAULT_SAMPLE_RATE / 50)
+            .flat_map(|n| if n % 2 == 0 { 3000i16 } else { -3000 }.to_ne_bytes())
+            .collect();
+        let mut events = Vec::new();
+        for n in 1..=duration / 20 {
+            buffer.write_all(&frame).unwrap();
+            let event = buffer.detector_event();
+            if event == SpeechDetectorEvent::Activity {
+                buffer.start_input();
+            }
+            if event != SpeechDetectorEvent::None {
+                events.push((n * 20, event));
+            }
+            if event == SpeechDetectorEvent::DurationTimeout {
+                break;
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn no_input_timeout_on_silence() {
+        let mut buffer = buffer();
+        buffer.no_input_timeout = 500;
+        write_silence(&mut buffer, 480);
+        assert_eq!(buffer.detector_event(), SpeechDetectorEvent::None);
+        write_silence(&mut buffer, 20);
+        assert_eq!(buffer.detector_event(), SpeechDetectorEvent::Noinput);
+    }
+
+    #[test]
+    fn speech_stops_no_input_timer() {
+        let mut buffer = buffer();
+        buffer.no_input_timeout = 500;
+        write_silence(&mut buffer, 100);
+        let events = write_speech(&mut buffer, 1000);
+        assert_eq!(events, [(300, SpeechDetectorEvent::Activity)]);
+        assert!(buffer.input_started());
+    }
+
+    #[test]
+    fn recognition_timeout_ends_long_speech() {
+        let mut buffer = buffer();
+        buffer.recognition_timeout = 1000;
+        write_silence(&mut buffer, 100);
+        let events = write_speech(&mut buffer, 3000);
+        assert_eq!(
+            events,
+            [
+                (300, SpeechDetectorEvent::Activity),
+                (1300, SpeechDetectorEvent::DurationTimeout)
+            ]
+        );
+    }
+
+    #[test]
+    fn speech_ends_at_max_utterance_duration() {
+        let mut buffer = buffer();
+        buffer.max_utterance_samples = DEFAULT_SAMPLE_RATE;
+        write_silence(&mut buffer, 100);
+        let events = write_speech(&mut buffer, 3000);
+        // The pre-roll takes the speech before Activity and the silence before it,
+        // so the utterance is full 1 s after the request started
+        assert_eq!(events[0], (300, SpeechDetectorEvent::Activity));
+        assert_eq!(
+            events[1],
+            (900, SpeechDetectorEvent::Inactivity { duration: 1000 })
+        );
+        assert_eq!(buffer.utterance.samples.len(), DEFAULT_SAMPLE_RATE);
+        buffer.recognize(1000);
+        let utterance = buffer.recognized.clone().unwrap();
+        assert_eq!((utterance.start, utterance.end), (0, DEFAULT_SAMPLE_RATE));
+    }
+
+    #[test]
+    fn hotword_miss_keeps_no_input_timer_running() {
+        let mut buffer = buffer();
+        buffer.hotword = true;
+        buffer.no_input_timeout = 200;
+        write_silence(&mut buffer, 100);
+        buffer.start_input();
+        buffer.restart_writing();
+        assert!(!buffer.input_started());
+        write_silence(&mut buffer, 100);
+        assert_eq!(buffer.detector_event(), SpeechDetectorEvent::Noinput);
+    }
+
+    #[test]
+    fn no_input_timer_waits_for_start_input_timers() {
+        let mut buffer = buffer();
+        buffer.no_input_timeout = 200;
+        buffer.timers_started.store(false, Ordering::Release);
+        write_silence(&mut buffer, 400);
+        assert_eq!(buffer.detector_event(), SpeechDetectorEvent::None);
+        buffer.start_input_timers();
+        write_silence(&mut buffer, 180);
+        assert_eq!(buffer.detector_event(), SpeechDetectorEvent::None);
+        write_silence(&mut buffer, 20);
+        assert_eq!(buffer.detector_event(), SpeechDetectorEvent::Noinput);
+    }
+
+    struct Unreachable;
+
+    impl StreamingBackend for Unreachable {
+        fn start(
+            &self,
+            _params: &RecognitionParams,
+            _sample_rate: usize,
+        ) -> Result<Box<dyn StreamingSession>, String> {
+            Err("connection refused".to_owned())
+        }
+    }
+
+    #[test]
+    fn failed_stream_start_completes_with_error_before_endpoint() {
+        let backend: Arc<dyn StreamingBackend> = Arc::new(Unreachable);
+        let mut buffer = buffer();
+        buffer.backend = Backend::Streaming(backend.clone());
+        buffer.stream =
+            Some(StreamingRecognition::start(backend, RecognitionParams::default(), 8000).unwrap());
+        buffer.recording = true;
+        for _ in 0..100 {
+            write_silence(&mut buffer, 20);
+            if buffer.detector_event() == SpeechDetectorEvent::Recognizing {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(buffer.detector_event(), SpeechDetectorEvent::Recognizing);
+        assert_eq!(buffer.load_result(), Some(RecognitionOutcome::Error));
+    }
+
+    #[test]
+    fn dropouts_keep_the_clock_running() {
+        let mut buffer = buffer();
+        buffer.no_input_timeout = 100;
+        write_silence(&mut buffer, 20);
+        for _ in 0..4 {
+            buffer.dropout();
+        }
+        assert_eq!(buffer.position, DEFAULT_SAMPLE_RATE / 10);
+        assert_eq!(buffer.detector_event(), SpeechDetectorEvent::Noinput);
+    }
+
+    #[test]
+    fn keep_listening_keeps_request_position() {
+        let mut buffer = buffer();
+        buffer.hotword = true;
+        write_silence(&mut buffer, 100);
+        buffer.restart_writing();
+        write_silence(&mut buffer, 100);
+        assert_eq!(buffer.position, DEFAULT_SAMPLE_RATE / 5);
+    }
+
+    #[test]
+    fn hotword_out_of_bounds_restarts_listening() {
+        let mut buffer = buffer();
+        buffer.hotword = true;
+        buffer.hotword_min_duration = 5000;
+        buffer.speech_incomplete_timeout = 500;
+        write_silence(&mut buffer, 100);
+        write_speech(&mut buffer, 600);
+        assert!(buffer.input_started());
+        write_silence(&mut buffer, 1000);
+        assert_eq!(buffer.detector_event(), SpeechDetectorEvent::None);
+        assert!(!buffer.input_started());
+        assert!(!buffer.recording);
+        // The detector starts over, new speech is Activity again
+        let events = write_speech(&mut buffer, 600);
+        assert_eq!(events, [(300, SpeechDetectorEvent::Activity)]);
+    }
+
+    #[test]
+    fn hotword_recognition_keeps_the_preroll() {
+        let mut buffer = buffer();
+        buffer.hotword = true;
+        write_silence(&mut buffer, 100);
+        write_speech(&mut buffer, 600);
+        buffer.recognize(600);
+        let position = buffer.position;
+        write_silence(&mut buffer, 200);
+        assert_eq!(buffer.position, position + DEFAULT_SAMPLE_RATE / 5);
+        assert_eq!(buffer.preroll.len(), DEFAULT_SAMPLE_RATE / 5);
+        buffer.restart_writing();
+        assert_eq!(buffer.preroll.len(), DEFAULT_SAMPLE_RATE / 5);
+        buffer.cancel();
+        assert_eq!(buffer.preroll.len(), 0);
+    }
+
+    #[test]
+    fn hotword_speech_is_bounded_by_recognition_timeout() {
+        let mut buffer = buffer();
+        buffer.hotword = true;
+        buffer.recognition_timeout = 200;
+        buffer.start_input();
+        write_silence(&mut buffer, 200);
+        assert_eq!(
+            buffer.detector_event(),
+            SpeechDetectorEvent::DurationTimeout
+        );
+    }
+
+    #[test]
+    fn hotword_miss_after_end_of_input_is_no_match() {
+        let mut buffer = buffer();
+        buffer.hotword = true;
+        buffer
+            .grammars
+            .activate("application/srgs+xml", "digits", DIGITS_GRAMMAR);
+        buffer.end_of_input();
+        assert_eq!(
+            buffer.outcome(result("один два")),
+            RecognitionOutcome::NoMatch
+        );
+    }
+
+    #[test]
+    fn waveform_is_saved_only_with_speech() {
+        let dir = std::env::temp_dir().join(format!("waveform-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut buffer = buffer();
+        buffer.config.waveform_dir = dir.to_str().unwrap().to_owned();
+        buffer.waveform_name = Some("utterance.wav".to_owned());
+        assert_eq!(buffer.save_waveform(), None);
+
+        buffer.utterance.samples = vec![100; 800];
+        let uri = buffer.save_waveform().unwrap();
+        let path = dir.join("utterance.wav");
+        assert_eq!(
+            uri,
+            format!("<file://{}>;size=1644;duration=100", path.display())
+        );
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let saved = loop {
+            let saved = crate::wav::read(&path).ok();
+            if saved
+                .as_ref()
+                .is_some_and(|(samples, _)| samples.len() == 800)
+                || std::time::Instant::now() > deadline
+            {
+                break saved;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        };
+        assert_eq!(saved, Some((vec![100; 800], DEFAULT_SAMPLE_RATE)));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,122 @@
+use crate::uni;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RecogParams {
+    pub sensitivity_level: Option<f32>,
+    pub no_input_timeout: Option<usize>,
+    pub speech_complete_timeout: Option<usize>,
+    pub speech_incomplete_timeout: Option<usize>,
+    pub recognition_timeout: Option<usize>,
+    pub start_input_timers: Option<bool>,
+    pub hotword: Option<bool>,
+    pub hotword_min_duration: Option<usize>,
+    pub hotword_max_duration: Option<usize>,
+    pub save_waveform: Option<bool>,
+    pub speech_language: Option<String>,
+    pub n_best_list_length: Option<usize>,
+    pub confidence_threshold: Option<f32>,
+}
+
+impl RecogParams {
+    pub unsafe fn update(&mut self, message: *const uni::mrcp_message_t) {
+        let recog_header =
+            uni::inline_mrcp_resource_header_get(message) as *const uni::mrcp_recog_header_t;
+        if recog_header.is_null() {
+            return;
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_SENSITIVITY_LEVEL) {
+            self.sensitivity_level = Some((*recog_header).sensitivity_level.clamp(0.0, 1.0));
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_NO_INPUT_TIMEOUT) {
+            self.no_input_timeout = Some((*recog_header).no_input_timeout);
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_RECOGNITION_TIMEOUT) {
+            self.recognition_timeout = Some((*recog_header).recognition_timeout);
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_START_INPUT_TIMERS) {
+            self.start_input_timers = Some((*recog_header).start_input_timers == uni::TRUE);
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_RECOGNITION_MODE) {
+            let mode = apt_string(&(*recog_header).recognition_mode);
+            self.hotword = Some(mode.trim().eq_ignore_ascii_case("hotword"));
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_HOTWORD_MIN_DURATION) {
+            self.hotword_min_duration = Some((*recog_header).hotword_min_duration);
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_HOTWORD_MAX_DURATION) {
+            self.hotword_max_duration = Some((*recog_header).hotword_max_duration);
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_SPEECH_LANGUAGE) {
+            self.speech_language = Some(apt_string(&(*recog_header).speech_language))
+                .filter(|language| !language.is_empty());
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_N_BEST_LIST_LENGTH) {
+            self.n_best_list_length = Some((*recog_header).n_best_list_length.max(1));
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_CONFIDENCE_THRESHOLD) {
+            self.confidence_threshold = Some((*recog_header).confidence_threshold.clamp(0.0, 1.0));
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_SAVE_WAVEFORM) {
+            self.save_waveform = Some((*recog_header).save_waveform == uni::TRUE);
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_SPEECH_COMPLETE_TIMEOUT) {
+            self.speech_complete_timeout = Some((*recog_header).speech_complete_timeout);
+        }
+        if has_property(message, uni::RECOGNIZER_HEADER_SPEECH_INCOMPLETE_TIMEOUT) {
+            self.speech_incomplete_timeout = Some((*recog_header).speech_incomplete_timeout);
+        }
+    }
+
+    pub unsafe fn merged(&self, message: *const uni::mrcp_message_t) -> Self {
+        let mut params = self.clone();
+        params.update(message);
+        params
+    }
+}
+
+unsafe fn has_property(message: *const uni::mrcp_message_t, id: u32) -> bool {
+    uni::inline_mrcp_resource_header_property_check(message, id as _) == uni::TRUE
+}
+
+pub unsafe fn apt_string(value: &uni::apt_str_t) -> String {
+    if value.buf.is_null() || value.length == 0 {
+        return String::new();
+    }
+    let bytes = std::slice::from_raw_parts(value.buf as *const u8, value.length);
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+pub unsafe fn content_type(message: *const uni::mrcp_message_t) -> String {
+    let generic_header = uni::inline_mrcp_generic_header_get(message);
+    if generic_header.is_null()
+        || uni::inline_mrcp_generic_header_property_check(
+            message,
+            uni::GENERIC_HEADER_CONTENT_TYPE as _,
+        ) == uni::FALSE
+    {
+        return String::new();
+    }
+    apt_string(&(*generic_header).content_type)
+}
+
+pub unsafe fn content_id(message: *const uni::mrcp_message_t) -> String {
+    let generic_header = uni::inline_mrcp_generic_header_get(message);
+    if generic_header.is_null()
+        || uni::inline_mrcp_generic_header_property_check(
+            message,
+            uni::GENERIC_HEADER_CONTENT_ID as _,
+        ) == uni::FALSE
+    {
+        return String::new();
+    }
+    apt_string(&(*generic_header).content_id)
+}
+
+pub unsafe fn input_waveform_uri(message: *const uni::mrcp_message_t) -> Option<String> {
+    let recog_header =
+        uni::inline_mrcp_resource_header_get(message) as *const uni::mrcp_recog_header_t;
+    if recog_header.is_null() || !has_property(message, uni::RECOGNIZER_HEADER_INPUT_WAVEFORM_URI) {
+        return None;
+    }
+    Some(apt_string(&(*recog_header).input_waveform_uri)).filter(|uri| !uri.is_empty())
+}
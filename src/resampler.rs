@@ -0,0 +1,227 @@
+use std::str::FromStr;
+
+const FIR_TAPS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResampleQuality {
+    /// Linear interpolation, cheap but aliases when downsampling.
+    Linear,
+    /// Windowed-sinc low-pass filter.
+    Fir,
+}
+
+impl FromStr for ResampleQuality {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Self::Linear),
+            "fir" => Ok(Self::Fir),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Streaming polyphase resampler between two fixed rates.
+/// Buffers are kept between frames so steady-state processing does not allocate.
+#[derive(Debug)]
+pub struct Resampler {
+    up: usize,
+    down: usize,
+    taps: usize,
+    /// `up` phases of `taps` coefficients each, newest sample first.
+    coefficients: Vec<f32>,
+    /// History of `taps - 1` samples followed by the current frame.
+    work: Vec<f32>,
+    /// Position of the next output sample at the upsampled rate, relative to the current frame.
+    time: usize,
+}
+
+impl Resampler {
+    pub fn new(from: usize, to: usize, quality: ResampleQuality) -> Self {
+        let divisor = gcd(from, to);
+        let (up, down) = (to / divisor, from / divisor);
+        let taps = match quality {
+            ResampleQuality::Linear => 2,
+            ResampleQuality::Fir => FIR_TAPS,
+        };
+        let coefficients = match quality {
+            ResampleQuality::Linear => linear_coefficients(up),
+            ResampleQuality::Fir => fir_coefficients(up, down, taps),
+        };
+        log::debug!(
+            "Resampler {} -> {} Hz, {:?}, {}/{}",
+            from,
+            to,
+            quality,
+            up,
+            down
+        );
+        Self {
+            up,
+            down,
+            taps,
+            coefficients,
+            work: vec![0.0; taps - 1],
+            time: 0,
+        }
+    }
+
+    pub fn process(&mut self, input: &[i16], output: &mut Vec<i16>) {
+        output.clear();
+        self.work.extend(input.iter().map(|&s| s as f32));
+        let history = self.taps - 1;
+        while self.time / self.up < input.len() {
+            let base = self.time / self.up + history;
+            let phase = self.time % self.up;
+            let coefficients = &self.coefficients[phase * self.taps..(phase + 1) * self.taps];
+            let sample: f32 = coefficients
+                .iter()
+                .enumerate()
+                .map(|(k, c)| c * self.work[base - k])
+                .sum();
+            output.push(sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+            self.time += self.down;
+        }
+        self.time -= input.len() * self.up;
+        let consumed = self.work.len() - history;
+        self.work.drain(..consumed);
+    }
+}
+
+fn linear_coefficients(up: usize) -> Vec<f32> {
+    (0..up)
+        .flat_map(|phase| {
+            let fraction = phase as f32 / up as f32;
+            [fraction, 1.0 - fraction]
+        })
+        .collect()
+}
+
+/// Blackman-windowed sinc low-pass at the upsampled rate, cut below the lower Nyquist frequency.
+fn fir_coefficients(up: usize, down: usize, taps: usize) -> Vec<f32> {
+    let len = taps * up;
+    let cutoff = 0.45 / up.max(down) as f64;
+    let center = (len - 1) as f64 / 2.0;
+    let prototype: Vec<f64> = (0..len)
+        .map(|n| {
+            let x = n as f64 - center;
+            let sinc = if x == 0.0 {
+                2.0 * cutoff
+            } else {
+                (2.0 * std::f64::consts::PI * cutoff * x).sin() / (std::f64::consts::PI * x)
+            };
+            let phase = 2.0 * std::f64::consts::PI * n as f64 / (len - 1) as f64;
+            let window = 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos();
+            sinc * window * up as f64
+        })
+        .collect();
+    let mut coefficients = Vec::with_capacity(len);
+    for phase in 0..up {
+        for k in 0..taps {
+            coefficients.push(prototype[phase + k * up] as f32);
+        }
+    }
+    coefficients
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, rate: usize, len: usize) -> Vec<i16> {
+        (0..len)
+            .map(|n| {
+                let t = n as f32 / rate as f32;
+                (8000.0 * (2.0 * std::f32::consts::PI * frequency * t).sin()) as i16
+            })
+            .collect()
+    }
+
+    fn rms(samples: &[i16]) -> f32 {
+        let sum: f32 = samples.iter().map(|&s| s as f32 * s as f32).sum();
+        (sum / samples.len() as f32).sqrt()
+    }
+
+    fn resample(from: usize, to: usize, quality: ResampleQuality, input: &[i16]) -> Vec<i16> {
+        let mut resampler = Resampler::new(from, to, quality);
+        let mut output = Vec::new();
+        let mut frame = Vec::new();
+        for chunk in input.chunks(from / 100) {
+            resampler.process(chunk, &mut frame);
+            output.extend_from_slice(&frame);
+        }
+        output
+    }
+
+    #[test]
+    fn parses_quality() {
+        assert_eq!("linear".parse(), Ok(ResampleQuality::Linear));
+        assert_eq!("fir".parse(), Ok(ResampleQuality::Fir));
+        assert_eq!("cubic".parse::<ResampleQuality>(), Err(()));
+    }
+
+    #[test]
+    fn frames_keep_the_rate_ratio() {
+        for quality in [ResampleQuality::Linear, ResampleQuality::Fir] {
+            let mut resampler = Resampler::new(8000, 16000, quality);
+            let mut output = Vec::new();
+            resampler.process(&[0; 80], &mut output);
+            assert_eq!(output.len(), 160);
+
+            let mut resampler = Resampler::new(44100, 16000, quality);
+            let total: usize = (0..100)
+                .map(|_| {
+                    resampler.process(&[0; 441], &mut output);
+                    output.len()
+                })
+                .sum();
+            assert_eq!(total, 16000);
+        }
+    }
+
+    #[test]
+    fn same_rate_linear_passes_samples_through() {
+        let input = sine(440.0, 8000, 800);
+        let output = resample(8000, 8000, ResampleQuality::Linear, &input);
+        assert_eq!(output.len(), input.len());
+        // One sample of delay from the history
+        assert_eq!(&output[1..], &input[..input.len() - 1]);
+    }
+
+    #[test]
+    fn keeps_passband_tone() {
+        let input = sine(440.0, 8000, 8000);
+        let output = resample(8000, 16000, ResampleQuality::Fir, &input);
+        let ratio = rms(&output[1600..]) / rms(&input[800..]);
+        assert!((ratio - 1.0).abs() < 0.05, "ratio {}", ratio);
+    }
+
+    #[test]
+    fn fir_attenuates_tone_above_target_nyquist() {
+        // 10 kHz aliases to 6 kHz at 16 kHz
+        let input = sine(10000.0, 48000, 48000);
+        let fir = resample(48000, 16000, ResampleQuality::Fir, &input);
+        let linear = resample(48000, 16000, ResampleQuality::Linear, &input);
+        assert!(rms(&fir[1600..]) < rms(&input) * 0.05);
+        assert!(rms(&fir[1600..]) < rms(&linear[1600..]) / 4.0);
+    }
+
+    #[test]
+    fn clamps_to_sample_range() {
+        let input: Vec<i16> = (0..1600)
+            .map(|n| if n % 16 < 8 { i16::MAX } else { i16::MIN })
+            .collect();
+        // Ringing around the square wave edges overshoots the sample range
+        let output = resample(8000, 16000, ResampleQuality::Fir, &input);
+        assert!(output.contains(&i16::MAX) && output.contains(&i16::MIN));
+    }
+}
@@ -1,4 +1,17 @@
-#![allow(dead_code)]
+use std::str::FromStr;
+
+use crate::config::EngineConfig;
+
+pub use combined::CombinedDetector;
+pub use energy::EnergyDetector;
+pub use spectral::SpectralDetector;
+
+mod combined;
+mod energy;
+mod spectral;
+
+pub const DEFAULT_SENSITIVITY: f32 = 0.5;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum SpeechDetectorEvent {
     None,
@@ -8,3 +21,409 @@ pub enum SpeechDetectorEvent {
     DurationTimeout,
     Recognizing,
 }
+
+pub trait SpeechDetector {
+    fn process(&mut self, samples: &[i16], frame_duration: usize) -> SpeechDetectorEvent;
+    /// Applies MRCP `Sensitivity-Level`, `DEFAULT_SENSITIVITY` keeps the configured thresholds.
+    fn set_sensitivity(&mut self, level: f32);
+    fn reset(&mut self);
+    /// Current noise floor estimate in dBFS.
+    fn noise_floor(&self) -> f32;
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DetectorKind {
+    Energy,
+    Spectral,
+    Combined,
+}
+
+impl FromStr for DetectorKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "energy" => Ok(Self::Energy),
+            "spectral" => Ok(Self::Spectral),
+            "combined" => Ok(Self::Combined),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn create(config: &EngineConfig) -> Box<dyn SpeechDetector> {
+    log::debug!("Creating {:?} speech detector", config.speech_detector);
+    match config.speech_detector {
+        DetectorKind::Energy => Box::new(EnergyDetector::new(config)),
+        DetectorKind::Spectral => Box::new(SpectralDetector::new(config)),
+        DetectorKind::Combined => Box::new(CombinedDetector::new(config)),
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum DetectorState {
+    Inactivity,
+    ActivityTransition,
+    Activity,
+    InactivityTransition,
+}
+
+/// Turns per-frame voicing decisions into detector events,
+/// debouncing onsets with the speech timeout and offsets with the hangover.
+#[derive(Debug)]
+pub struct ActivityTracker {
+    base_speech_timeout: usize,
+    speech_timeout: usize,
+    silence_timeout: usize,
+    state: DetectorState,
+    duration: usize,
+    speech_duration: usize,
+}
+
+impl ActivityTracker {
+    pub fn new(speech_timeout: usize, silence_timeout: usize) -> Self {
+        Self {
+            base_speech_timeout: speech_timeout,
+            speech_timeout,
+            silence_timeout,
+            state: DetectorState::Inactivity,
+            duration: 0,
+            speech_duration: 0,
+        }
+    }
+
+    pub fn set_sensitivity(&mut self, level: f32) {
+        let shift = level.clamp(0.0, 1.0) - DEFAULT_SENSITIVITY;
+        self.speech_timeout = (self.base_speech_timeout as f32 * (1.0 - shift)) as usize;
+    }
+
+    pub fn in_speech(&self) -> bool {
+        self.state != DetectorState::Inactivity
+    }
+
+    pub fn reset(&mut self) {
+        self.state = DetectorState::Inactivity;
+        self.duration = 0;
+        self.speech_duration = 0;
+    }
+
+    pub fn update(&mut self, voiced: bool, frame_duration: usize) -> SpeechDetectorEvent {
+        match self.state {
+            DetectorState::Inactivity => {
+                if voiced {
+                    self.state = DetectorState::ActivityTransition;
+                    self.duration = frame_duration;
+                }
+                SpeechDetectorEvent::None
+            }
+            DetectorState::ActivityTransition => {
+                if !voiced {
+                    self.state = DetectorState::Inactivity;
+                    self.duration = 0;
+                    return SpeechDetectorEvent::None;
+                }
+                self.duration += frame_duration;
+                if self.duration < self.speech_timeout {
+                    return SpeechDetectorEvent::None;
+                }
+                self.state = DetectorState::Activity;
+                self.speech_duration = self.duration;
+                SpeechDetectorEvent::Activity
+            }
+            DetectorState::Activity => {
+                self.speech_duration += frame_duration;
+                if !voiced {
+                    self.state = DetectorState::InactivityTransition;
+                    self.duration = frame_duration;
+                }
+                SpeechDetectorEvent::None
+            }
+            DetectorState::InactivityTransition => {
+                self.speech_duration += frame_duration;
+                if voiced {
+                    self.state = DetectorState::Activity;
+                    return SpeechDetectorEvent::None;
+                }
+                self.duration += frame_duration;
+                if self.duration < self.silence_timeout {
+                    return SpeechDetectorEvent::None;
+                }
+                self.state = DetectorState::Inactivity;
+                SpeechDetectorEvent::Inactivity {
+                    duration: self.speech_duration - self.duration,
+                }
+            }
+        }
+    }
+}
+
+const NOISE_FLOOR_MIN: f32 = -90.0;
+const NOISE_FLOOR_INIT_FRAMES: usize = 20;
+const NOISE_FLOOR_RISE: f32 = 0.02;
+const NOISE_FLOOR_FALL: f32 = 0.2;
+/// Frames of speech after which the floor moves up to the quietest of them.
+const NOISE_FLOOR_SPEECH_WINDOW: usize = 200;
+const NOISE_FLOOR_SPEECH_RISE: f32 = 0.5;
+
+/// Estimates line noise from the first frames of a channel and keeps
+/// tracking it between utterances: slowly up, quickly down.
+/// During long speech it rises towards the quietest frames, so noise that got
+/// louder than the floor does not hold the detector in speech for good.
+#[derive(Debug)]
+pub struct NoiseFloor {
+    level: f32,
+    frames: usize,
+    speech_frames: usize,
+    speech_min: f32,
+}
+
+impl NoiseFloor {
+    pub fn new() -> Self {
+        Self {
+            level: NOISE_FLOOR_MIN,
+            frames: 0,
+            speech_frames: 0,
+            speech_min: f32::INFINITY,
+        }
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    pub fn update(&mut self, level: f32, in_speech: bool) {
+        if in_speech {
+            self.update_in_speech(level);
+            return;
+        }
+        self.speech_frames = 0;
+        self.speech_min = f32::INFINITY;
+        let level = level.max(NOISE_FLOOR_MIN);
+        if self.frames < NOISE_FLOOR_INIT_FRAMES {
+            self.level = (self.level * self.frames as f32 + level) / (self.frames + 1) as f32;
+            self.frames += 1;
+            if self.frames == NOISE_FLOOR_INIT_FRAMES {
+                log::debug!("Initial noise floor {:.1} dBFS", self.level);
+            }
+            return;
+        }
+        let rate = if level > self.level {
+            NOISE_FLOOR_RISE
+        } else {
+            NOISE_FLOOR_FALL
+        };
+        self.level += (level - self.level) * rate;
+    }
+
+    /// Pauses between words bring the quietest frame of a window down to the line noise.
+    fn update_in_speech(&mut self, level: f32) {
+        self.speech_min = self.speech_min.min(level.max(NOISE_FLOOR_MIN));
+        self.speech_frames += 1;
+        if self.speech_frames < NOISE_FLOOR_SPEECH_WINDOW {
+            return;
+        }
+        if self.speech_min > self.level {
+            self.level += (self.speech_min - self.level) * NOISE_FLOOR_SPEECH_RISE;
+            log::debug!("Noise floor {:.1} dBFS in long speech", self.level);
+        }
+        self.speech_frames = 0;
+        self.speech_min = f32::INFINITY;
+    }
+}
+
+/// Frame energy in dBFS, `-inf` for digital silence.
+pub fn frame_level(samples: &[i16]) -> f32 {
+    if samples.is_empty() {
+        return f32::NEG_INFINITY;
+    }
+    let energy = samples
+        .iter()
+        .map(|&s| {
+            let s = s as f64;
+            s * s
+        })
+        .sum::<f64>()
+        / samples.len() as f64;
+    (10.0 * (energy / (i16::MAX as f64 * i16::MAX as f64)).log10()) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: usize = 20;
+
+    /// Non-`None` events with the index of the frame they came on.
+    fn events(
+        tracker: &mut ActivityTracker,
+        voicing: &[bool],
+    ) -> Vec<(usize, SpeechDetectorEvent)> {
+        voicing
+            .iter()
+            .enumerate()
+            .map(|(n, &voiced)| (n, tracker.update(voiced, FRAME)))
+            .filter(|(_, event)| *event != SpeechDetectorEvent::None)
+            .collect()
+    }
+
+    fn frames(voiced: bool, count: usize) -> Vec<bool> {
+        vec![voiced; count]
+    }
+
+    #[test]
+    fn activity_after_speech_timeout() {
+        let mut tracker = ActivityTracker::new(100, 200);
+        let voicing = frames(true, 6);
+        assert_eq!(
+            events(&mut tracker, &voicing),
+            [(4, SpeechDetectorEvent::Activity)]
+        );
+        assert!(tracker.in_speech());
+    }
+
+    #[test]
+    fn short_noise_is_not_activity() {
+        let mut tracker = ActivityTracker::new(100, 200);
+        let voicing = [frames(true, 4), frames(false, 1), frames(true, 4)].concat();
+        assert_eq!(events(&mut tracker, &voicing), []);
+    }
+
+    #[test]
+    fn inactivity_after_hangover_reports_speech_duration() {
+        let mut tracker = ActivityTracker::new(100, 200);
+        let voicing = [frames(true, 10), frames(false, 10)].concat();
+        assert_eq!(
+            events(&mut tracker, &voicing),
+            [
+                (4, SpeechDetectorEvent::Activity),
+                (19, SpeechDetectorEvent::Inactivity { duration: 200 }),
+            ]
+        );
+        assert!(!tracker.in_speech());
+    }
+
+    #[test]
+    fn pause_shorter_than_hangover_continues_speech() {
+        let mut tracker = ActivityTracker::new(100, 200);
+        let voicing = [
+            frames(true, 10),
+            frames(false, 9),
+            frames(true, 5),
+            frames(false, 10),
+        ]
+        .concat();
+        assert_eq!(
+            events(&mut tracker, &voicing),
+            [
+                (4, SpeechDetectorEvent::Activity),
+                (33, SpeechDetectorEvent::Inactivity { duration: 480 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_drops_speech_in_progress() {
+        let mut tracker = ActivityTracker::new(100, 200);
+        events(&mut tracker, &frames(true, 10));
+        tracker.reset();
+        assert!(!tracker.in_speech());
+        assert_eq!(events(&mut tracker, &frames(false, 20)), []);
+    }
+
+    #[test]
+    fn sensitivity_scales_speech_timeout() {
+        let mut tracker = ActivityTracker::new(100, 200);
+        tracker.set_sensitivity(1.0);
+        assert_eq!(
+            events(&mut tracker, &frames(true, 6)),
+            [(2, SpeechDetectorEvent::Activity)]
+        );
+        let mut tracker = ActivityTracker::new(100, 200);
+        tracker.set_sensitivity(0.0);
+        assert_eq!(
+            events(&mut tracker, &frames(true, 10)),
+            [(7, SpeechDetectorEvent::Activity)]
+        );
+    }
+
+    #[test]
+    fn noise_floor_starts_from_average_of_first_frames() {
+        let mut noise_floor = NoiseFloor::new();
+        assert_eq!(noise_floor.level(), NOISE_FLOOR_MIN);
+        for n in 0..NOISE_FLOOR_INIT_FRAMES {
+            let level = if n % 2 == 0 { -50.0 } else { -70.0 };
+            noise_floor.update(level, false);
+        }
+        assert!((noise_floor.level() + 60.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn noise_floor_rises_slowly_and_falls_quickly() {
+        let mut noise_floor = NoiseFloor::new();
+        for _ in 0..NOISE_FLOOR_INIT_FRAMES {
+            noise_floor.update(-60.0, false);
+        }
+        noise_floor.update(-40.0, false);
+        assert!((noise_floor.level() + 59.6).abs() < 0.01);
+        noise_floor.update(-79.6, false);
+        assert!((noise_floor.level() + 63.6).abs() < 0.01);
+    }
+
+    #[test]
+    fn noise_floor_ignores_speech_and_digital_silence() {
+        let mut noise_floor = NoiseFloor::new();
+        for _ in 0..NOISE_FLOOR_INIT_FRAMES {
+            noise_floor.update(-60.0, false);
+        }
+        noise_floor.update(-10.0, true);
+        assert!((noise_floor.level() + 60.0).abs() < 0.01);
+        for _ in 0..100 {
+            noise_floor.update(f32::NEG_INFINITY, false);
+        }
+        assert!(noise_floor.level() >= NOISE_FLOOR_MIN);
+        assert!((noise_floor.level() - NOISE_FLOOR_MIN).abs() < 0.01);
+    }
+
+    #[test]
+    fn noise_floor_rises_in_long_steady_speech() {
+        let mut noise_floor = NoiseFloor::new();
+        for _ in 0..NOISE_FLOOR_INIT_FRAMES {
+            noise_floor.update(-60.0, false);
+        }
+        for _ in 0..NOISE_FLOOR_SPEECH_WINDOW - 1 {
+            noise_floor.update(-30.0, true);
+        }
+        assert!((noise_floor.level() + 60.0).abs() < 0.01);
+        noise_floor.update(-30.0, true);
+        assert!((noise_floor.level() + 45.0).abs() < 0.01);
+        for _ in 0..NOISE_FLOOR_SPEECH_WINDOW * 4 {
+            noise_floor.update(-30.0, true);
+        }
+        assert!(noise_floor.level() > -32.0);
+    }
+
+    #[test]
+    fn noise_floor_holds_through_pauses_in_speech() {
+        let mut noise_floor = NoiseFloor::new();
+        for _ in 0..NOISE_FLOOR_INIT_FRAMES {
+            noise_floor.update(-60.0, false);
+        }
+        for n in 0..NOISE_FLOOR_SPEECH_WINDOW * 5 {
+            let level = if n % 50 == 0 { -61.0 } else { -20.0 };
+            noise_floor.update(level, true);
+        }
+        assert!((noise_floor.level() + 60.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn frame_level_in_dbfs() {
+        assert_eq!(frame_level(&[]), f32::NEG_INFINITY);
+        assert_eq!(frame_level(&[0; 160]), f32::NEG_INFINITY);
+        let full_scale: Vec<i16> = (0..160)
+            .map(|n| if n % 2 == 0 { i16::MAX } else { -i16::MAX })
+            .collect();
+        assert!(frame_level(&full_scale).abs() < 0.01);
+        let half_scale: Vec<i16> = full_scale.iter().map(|s| s / 2).collect();
+        assert!((frame_level(&half_scale) + 6.02).abs() < 0.01);
+    }
+}
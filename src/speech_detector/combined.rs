@@ -0,0 +1,122 @@
+use super::{
+    ActivityTracker, EnergyDetector, SpectralDetector, SpeechDetector, SpeechDetectorEvent,
+};
+use crate::config::EngineConfig;
+
+/// Reports speech only when both the energy and the spectral detectors agree,
+/// which keeps loud stationary noise on GSM trunks from triggering barge-in.
+#[derive(Debug)]
+pub struct CombinedDetector {
+    tracker: ActivityTracker,
+    energy: EnergyDetector,
+    spectral: SpectralDetector,
+}
+
+impl CombinedDetector {
+    pub fn new(config: &EngineConfig) -> Self {
+        Self {
+            tracker: ActivityTracker::new(config.vad_speech_timeout, config.vad_hangover),
+            energy: EnergyDetector::new(config),
+            spectral: SpectralDetector::new(config),
+        }
+    }
+}
+
+impl SpeechDetector for CombinedDetector {
+    fn process(&mut self, samples: &[i16], frame_duration: usize) -> SpeechDetectorEvent {
+        let in_speech = self.tracker.in_speech();
+        // Both have to see every frame to keep their noise floors up to date.
+        let energy_voiced = self.energy.is_voiced(samples, in_speech);
+        let spectral_voiced = self.spectral.is_voiced(samples, in_speech);
+        let voiced = energy_voiced && spectral_voiced;
+        self.tracker.update(voiced, frame_duration)
+    }
+
+    fn set_sensitivity(&mut self, level: f32) {
+        self.energy.set_level_sensitivity(level);
+        self.spectral.set_flatness_sensitivity(level);
+        self.tracker.set_sensitivity(level);
+    }
+
+    fn reset(&mut self) {
+        self.tracker.reset();
+    }
+
+    fn noise_floor(&self) -> f32 {
+        self.energy.noise_floor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::speech_detector::frame_level;
+
+    const FRAME: usize = 20;
+
+    /// A 20 ms frame of a 500 Hz tone at about the given level in dBFS.
+    fn tone(level: f32) -> Vec<i16> {
+        let amplitude = i16::MAX as f32 * 10f32.powf(level / 20.0) * std::f32::consts::SQRT_2;
+        (0..160)
+            .map(|n| (amplitude * (2.0 * std::f32::consts::PI * n as f32 / 16.0).sin()) as i16)
+            .collect()
+    }
+
+    /// A 20 ms frame of deterministic white noise, peak amplitude as given.
+    fn noise(seed: &mut u32, amplitude: f32) -> Vec<i16> {
+        (0..160)
+            .map(|_| {
+                *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (((*seed >> 16) as f32 / 32768.0 - 1.0) * amplitude) as i16
+            })
+            .collect()
+    }
+
+    fn detector_on_line_noise(seed: &mut u32) -> CombinedDetector {
+        let mut detector = CombinedDetector::new(&EngineConfig::default());
+        for _ in 0..50 {
+            let frame = noise(seed, 10.0);
+            assert_eq!(detector.process(&frame, FRAME), SpeechDetectorEvent::None);
+        }
+        detector
+    }
+
+    fn detects_activity(detector: &mut CombinedDetector, frame: &[i16]) -> bool {
+        (0..50).any(|_| detector.process(frame, FRAME) == SpeechDetectorEvent::Activity)
+    }
+
+    #[test]
+    fn speech_when_both_agree() {
+        let mut seed = 1;
+        let mut detector = detector_on_line_noise(&mut seed);
+        assert!(detects_activity(&mut detector, &tone(-20.0)));
+    }
+
+    #[test]
+    fn loud_noise_is_vetoed_by_spectral() {
+        let mut seed = 1;
+        let mut detector = detector_on_line_noise(&mut seed);
+        let frame = noise(&mut seed, 10000.0);
+        assert!(frame_level(&frame) > -20.0);
+        assert!(detector.energy.is_voiced(&frame, false));
+        assert!(!detector.spectral.is_voiced(&frame, false));
+        assert!(!detects_activity(&mut detector, &frame));
+    }
+
+    #[test]
+    fn quiet_tone_is_vetoed_by_energy() {
+        let mut seed = 1;
+        let mut detector = detector_on_line_noise(&mut seed);
+        let frame = tone(-58.0);
+        assert!(!detector.energy.is_voiced(&frame, false));
+        assert!(detector.spectral.is_voiced(&frame, false));
+        assert!(!detects_activity(&mut detector, &frame));
+    }
+
+    #[test]
+    fn silence_is_silence() {
+        let mut seed = 1;
+        let mut detector = detector_on_line_noise(&mut seed);
+        assert!(!detects_activity(&mut detector, &[0; 160]));
+    }
+}
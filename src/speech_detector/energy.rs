@@ -0,0 +1,138 @@
+use super::{
+    frame_level, ActivityTracker, NoiseFloor, SpeechDetector, SpeechDetectorEvent,
+    DEFAULT_SENSITIVITY,
+};
+use crate::config::EngineConfig;
+
+const SENSITIVITY_MARGIN_RANGE: f32 = 12.0;
+const MIN_NOISE_MARGIN: f32 = 3.0;
+
+/// Voiced frames are those louder than the noise floor by the margin,
+/// but never quieter than the absolute level threshold.
+#[derive(Debug)]
+pub struct EnergyDetector {
+    tracker: ActivityTracker,
+    noise_floor: NoiseFloor,
+    level_threshold: f32,
+    base_noise_margin: f32,
+    noise_margin: f32,
+}
+
+impl EnergyDetector {
+    pub fn new(config: &EngineConfig) -> Self {
+        Self {
+            tracker: ActivityTracker::new(config.vad_speech_timeout, config.vad_hangover),
+            noise_floor: NoiseFloor::new(),
+            level_threshold: config.vad_threshold,
+            base_noise_margin: config.vad_noise_margin,
+            noise_margin: config.vad_noise_margin,
+        }
+    }
+
+    pub fn is_voiced(&mut self, samples: &[i16], in_speech: bool) -> bool {
+        let level = frame_level(samples);
+        self.noise_floor.update(level, in_speech);
+        level
+            >= self
+                .level_threshold
+                .max(self.noise_floor.level() + self.noise_margin)
+    }
+
+    pub fn set_level_sensitivity(&mut self, level: f32) {
+        let shift = level.clamp(0.0, 1.0) - DEFAULT_SENSITIVITY;
+        self.noise_margin =
+            (self.base_noise_margin - shift * SENSITIVITY_MARGIN_RANGE).max(MIN_NOISE_MARGIN);
+        log::debug!(
+            "Sensitivity {}: {} dB above noise floor",
+            level,
+            self.noise_margin
+        );
+    }
+}
+
+impl SpeechDetector for EnergyDetector {
+    fn process(&mut self, samples: &[i16], frame_duration: usize) -> SpeechDetectorEvent {
+        let voiced = self.is_voiced(samples, self.tracker.in_speech());
+        self.tracker.update(voiced, frame_duration)
+    }
+
+    fn set_sensitivity(&mut self, level: f32) {
+        self.set_level_sensitivity(level);
+        self.tracker.set_sensitivity(level);
+    }
+
+    fn reset(&mut self) {
+        self.tracker.reset();
+    }
+
+    fn noise_floor(&self) -> f32 {
+        self.noise_floor.level()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: usize = 20;
+
+    /// A frame of a square wave at about the given level in dBFS.
+    fn frame(level: f32) -> Vec<i16> {
+        let amplitude = (i16::MAX as f32 * 10f32.powf(level / 20.0)).round() as i16;
+        (0..160)
+            .map(|n| if n % 2 == 0 { amplitude } else { -amplitude })
+            .collect()
+    }
+
+    fn detector_on_noise(noise_level: f32) -> EnergyDetector {
+        let mut detector = EnergyDetector::new(&EngineConfig::default());
+        let noise = frame(noise_level);
+        for _ in 0..50 {
+            assert_eq!(detector.process(&noise, FRAME), SpeechDetectorEvent::None);
+        }
+        assert!((detector.noise_floor() - frame_level(&noise)).abs() < 0.01);
+        detector
+    }
+
+    fn detects_activity(detector: &mut EnergyDetector, level: f32) -> bool {
+        let speech = frame(level);
+        (0..50).any(|_| detector.process(&speech, FRAME) == SpeechDetectorEvent::Activity)
+    }
+
+    fn detects_speech(noise_level: f32, level: f32, sensitivity: f32) -> bool {
+        let mut detector = detector_on_noise(noise_level);
+        detector.set_sensitivity(sensitivity);
+        detects_activity(&mut detector, level)
+    }
+
+    #[test]
+    fn quiet_line_uses_level_threshold() {
+        assert!(!detects_speech(-80.0, -60.0, DEFAULT_SENSITIVITY));
+        assert!(detects_speech(-80.0, -50.0, DEFAULT_SENSITIVITY));
+    }
+
+    #[test]
+    fn noisy_line_needs_margin_above_noise_floor() {
+        assert!(!detects_speech(-40.0, -30.0, DEFAULT_SENSITIVITY));
+        assert!(detects_speech(-40.0, -25.0, DEFAULT_SENSITIVITY));
+    }
+
+    #[test]
+    fn sensitivity_narrows_noise_margin() {
+        assert!(detects_speech(-40.0, -30.0, 1.0));
+        assert!(!detects_speech(-40.0, -25.0, 0.0));
+    }
+
+    #[test]
+    fn noise_floor_holds_during_speech() {
+        let mut detector = detector_on_noise(-60.0);
+        assert!(detects_activity(&mut detector, -20.0));
+        let noise_floor = detector.noise_floor();
+        let speech = frame(-20.0);
+        for _ in 0..100 {
+            detector.process(&speech, FRAME);
+        }
+        assert_eq!(detector.noise_floor(), noise_floor);
+        assert!(noise_floor < -55.0);
+    }
+}
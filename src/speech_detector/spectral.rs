@@ -0,0 +1,187 @@
+use super::{
+    frame_level, ActivityTracker, NoiseFloor, SpeechDetector, SpeechDetectorEvent,
+    DEFAULT_SENSITIVITY,
+};
+use crate::{config::EngineConfig, fft};
+
+const MIN_FFT_SIZE: usize = 256;
+const MIN_LEVEL: f32 = -60.0;
+const MIN_NOISE_MARGIN: f32 = 3.0;
+const MAX_ZERO_CROSSING_RATE: f32 = 0.35;
+const SENSITIVITY_FLATNESS_RANGE: f32 = 0.4;
+
+/// Tells tonal speech from broadband noise by zero-crossing rate and spectral flatness.
+#[derive(Debug)]
+pub struct SpectralDetector {
+    tracker: ActivityTracker,
+    noise_floor: NoiseFloor,
+    base_flatness_threshold: f32,
+    flatness_threshold: f32,
+    re: Vec<f32>,
+    im: Vec<f32>,
+}
+
+impl SpectralDetector {
+    pub fn new(config: &EngineConfig) -> Self {
+        Self {
+            tracker: ActivityTracker::new(config.vad_speech_timeout, config.vad_hangover),
+            noise_floor: NoiseFloor::new(),
+            base_flatness_threshold: config.vad_flatness_threshold,
+            flatness_threshold: config.vad_flatness_threshold,
+            re: vec![0.0; MIN_FFT_SIZE],
+            im: vec![0.0; MIN_FFT_SIZE],
+        }
+    }
+
+    pub fn is_voiced(&mut self, samples: &[i16], in_speech: bool) -> bool {
+        let level = frame_level(samples);
+        self.noise_floor.update(level, in_speech);
+        if samples.len() < 2 || level < MIN_LEVEL.max(self.noise_floor.level() + MIN_NOISE_MARGIN) {
+            return false;
+        }
+        zero_crossing_rate(samples) <= MAX_ZERO_CROSSING_RATE
+            && self.spectral_flatness(samples) <= self.flatness_threshold
+    }
+
+    pub fn set_flatness_sensitivity(&mut self, level: f32) {
+        let shift = level.clamp(0.0, 1.0) - DEFAULT_SENSITIVITY;
+        self.flatness_threshold =
+            (self.base_flatness_threshold + shift * SENSITIVITY_FLATNESS_RANGE).clamp(0.0, 1.0);
+        log::debug!(
+            "Sensitivity {}: flatness threshold {}",
+            level,
+            self.flatness_threshold
+        );
+    }
+
+    /// Ratio of geometric to arithmetic mean of the power spectrum:
+    /// close to 1 for white noise, close to 0 for voiced speech.
+    fn spectral_flatness(&mut self, samples: &[i16]) -> f32 {
+        // Wideband frames get a longer transform once, on the first such frame
+        let size = samples.len().next_power_of_two().max(MIN_FFT_SIZE);
+        if size > self.re.len() {
+            self.re.resize(size, 0.0);
+            self.im.resize(size, 0.0);
+        }
+        let len = samples.len();
+        for (i, (re, im)) in self.re.iter_mut().zip(self.im.iter_mut()).enumerate() {
+            *re = if i < len {
+                let window = 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / len as f32).cos();
+                samples[i] as f32 * window
+            } else {
+                0.0
+            };
+            *im = 0.0;
+        }
+        fft::fft(&mut self.re, &mut self.im);
+        let bins = self.re.len() / 2;
+        let (mut log_sum, mut sum) = (0.0f64, 0.0f64);
+        for k in 1..=bins {
+            let power = (self.re[k] * self.re[k] + self.im[k] * self.im[k]) as f64 + 1e-3;
+            log_sum += power.ln();
+            sum += power;
+        }
+        ((log_sum / bins as f64).exp() / (sum / bins as f64)) as f32
+    }
+}
+
+impl SpeechDetector for SpectralDetector {
+    fn process(&mut self, samples: &[i16], frame_duration: usize) -> SpeechDetectorEvent {
+        let voiced = self.is_voiced(samples, self.tracker.in_speech());
+        self.tracker.update(voiced, frame_duration)
+    }
+
+    fn set_sensitivity(&mut self, level: f32) {
+        self.set_flatness_sensitivity(level);
+        self.tracker.set_sensitivity(level);
+    }
+
+    fn reset(&mut self) {
+        self.tracker.reset();
+    }
+
+    fn noise_floor(&self) -> f32 {
+        self.noise_floor.level()
+    }
+}
+
+fn zero_crossing_rate(samples: &[i16]) -> f32 {
+    let crossings = samples
+        .windows(2)
+        .filter(|pair| (pair[0] >= 0) != (pair[1] >= 0))
+        .count();
+    crossings as f32 / (samples.len() - 1) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: usize = 20;
+
+    fn amplitude(level: f32) -> f32 {
+        i16::MAX as f32 * 10f32.powf(level / 20.0) * std::f32::consts::SQRT_2
+    }
+
+    /// A 20 ms frame of a 500 Hz tone at about the given level in dBFS.
+    fn tone(level: f32) -> Vec<i16> {
+        let amplitude = amplitude(level);
+        (0..160)
+            .map(|n| (amplitude * (2.0 * std::f32::consts::PI * n as f32 / 16.0).sin()) as i16)
+            .collect()
+    }
+
+    /// A 20 ms frame of deterministic white noise.
+    fn noise(seed: &mut u32, level: f32) -> Vec<i16> {
+        // Uniform noise has a peak of sqrt(3) times its RMS
+        let amplitude = amplitude(level) * 3f32.sqrt() / std::f32::consts::SQRT_2;
+        (0..160)
+            .map(|_| {
+                *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (((*seed >> 16) as f32 / 32768.0 - 1.0) * amplitude) as i16
+            })
+            .collect()
+    }
+
+    fn detector_on_line_noise(seed: &mut u32) -> SpectralDetector {
+        let mut detector = SpectralDetector::new(&EngineConfig::default());
+        for _ in 0..50 {
+            let frame = noise(seed, -75.0);
+            assert_eq!(detector.process(&frame, FRAME), SpeechDetectorEvent::None);
+        }
+        detector
+    }
+
+    #[test]
+    fn tone_is_speech() {
+        let mut seed = 1;
+        let mut detector = detector_on_line_noise(&mut seed);
+        let frame = tone(-20.0);
+        assert!(detector.spectral_flatness(&frame) < 0.1);
+        assert!((0..50).any(|_| detector.process(&frame, FRAME) == SpeechDetectorEvent::Activity));
+    }
+
+    #[test]
+    fn loud_noise_is_not_speech() {
+        let mut seed = 1;
+        let mut detector = detector_on_line_noise(&mut seed);
+        for _ in 0..50 {
+            let frame = noise(&mut seed, -20.0);
+            assert!((frame_level(&frame) + 20.0).abs() < 1.0);
+            assert!(!detector.is_voiced(&frame, false));
+        }
+    }
+
+    #[test]
+    fn silence_is_not_speech() {
+        let mut seed = 1;
+        let mut detector = detector_on_line_noise(&mut seed);
+        for _ in 0..50 {
+            assert_eq!(
+                detector.process(&[0; 160], FRAME),
+                SpeechDetectorEvent::None
+            );
+        }
+        assert!(!detector.is_voiced(&tone(-70.0), false));
+    }
+}
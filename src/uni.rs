@@ -58,7 +58,11 @@ pub unsafe fn inline_mpf_codec_capabilities_add(
     );
     (*attribs).sample_rates = sample_rates;
     (*attribs).bits_per_sample = 0;
-    // (*attribs).frame_duration = CODEC_FRAME_TIME_BASE as _; // In version 1.8.0 was introduced 'frame_duration' codec property. 10 ms was hardcoded in earlier versions
+    // In version 1.8.0 was introduced 'frame_duration' codec property. 10 ms was hardcoded in earlier versions
+    #[cfg(feature = "frame-duration")]
+    {
+        (*attribs).frame_duration = CODEC_FRAME_TIME_BASE as _;
+    }
     TRUE
 }
 
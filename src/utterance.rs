@@ -0,0 +1,29 @@
+/// Contiguous PCM audio of one utterance handed to the recognition step.
+/// `start` and `end` are sample offsets from the beginning of the RECOGNIZE request.
+#[derive(Debug, Clone, Default)]
+pub struct Utterance {
+    pub samples: Vec<i16>,
+    pub sample_rate: usize,
+    pub start: usize,
+    pub end: usize,
+    /// Frames lost or missing in the stream while recording.
+    pub dropouts: usize,
+    pub dropout_duration: usize,
+}
+
+impl Utterance {
+    pub fn new(sample_rate: usize) -> Self {
+        Self {
+            sample_rate,
+            ..Default::default()
+        }
+    }
+
+    /// Duration in ms.
+    pub fn duration(&self) -> usize {
+        if self.sample_rate == 0 {
+            return 0;
+        }
+        self.samples.len() * 1000 / self.sample_rate
+    }
+}
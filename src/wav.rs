@@ -0,0 +1,302 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+const HEADER_SIZE: usize = 44;
+
+/// Streams 16-bit mono PCM into a RIFF WAVE file,
+/// the header sizes are filled in on `finish` or drop.
+pub struct WavWriter<W: Write + Seek = BufWriter<File>> {
+    out: W,
+    data_size: usize,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: usize) -> std::io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: usize) -> std::io::Result<Self> {
+        out.write_all(b"RIFF")?;
+        out.write_all(&(HEADER_SIZE as u32 - 8).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM, mono
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&(sample_rate as u32).to_le_bytes())?;
+        out.write_all(&(sample_rate as u32 * 2).to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { out, data_size: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> std::io::Result<()> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_size += samples.len() * 2;
+        Ok(())
+    }
+
+    /// Completes the header, returns the file size in bytes.
+    pub fn finish(&mut self) -> std::io::Result<usize> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&((HEADER_SIZE - 8 + self.data_size) as u32).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.out.write_all(&(self.data_size as u32).to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(HEADER_SIZE + self.data_size)
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        self.finish().ok();
+    }
+}
+
+/// Size in bytes of the RIFF WAVE file `write` makes of this many samples.
+pub fn file_size(samples: usize) -> usize {
+    HEADER_SIZE + samples * 2
+}
+
+/// Writes 16-bit mono PCM as a RIFF WAVE file, returns the file size in bytes.
+pub fn write(path: &Path, samples: &[i16], sample_rate: usize) -> std::io::Result<usize> {
+    let mut writer = WavWriter::create(path, sample_rate)?;
+    writer.write_samples(samples)?;
+    writer.finish()
+}
+
+/// RIFF WAVE file contents of 16-bit mono PCM.
+pub fn encode(samples: &[i16], sample_rate: usize) -> std::io::Result<Vec<u8>> {
+    let mut out = Cursor::new(Vec::with_capacity(HEADER_SIZE + samples.len() * 2));
+    let mut writer = WavWriter::new(&mut out, sample_rate)?;
+    writer.write_samples(samples)?;
+    writer.finish()?;
+    drop(writer);
+    Ok(out.into_inner())
+}
+
+/// Reads 16-bit PCM from a RIFF WAVE file, mixing multiple channels down to mono.
+/// Returns the samples and the sample rate.
+pub fn read(path: &Path) -> std::io::Result<(Vec<i16>, usize)> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(invalid("not a RIFF WAVE file"));
+    }
+    let mut format = None;
+    let mut rest = &data[12..];
+    while rest.len() >= 8 {
+        let id = &rest[..4];
+        let size = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let body = &rest[8..(8 + size).min(rest.len())];
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let tag = u16::from_le_bytes([body[0], body[1]]);
+                let channels = u16::from_le_bytes([body[2], body[3]]) as usize;
+                let rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]) as usize;
+                let bits = u16::from_le_bytes([body[14], body[15]]);
+                // PCM or WAVE_FORMAT_EXTENSIBLE
+                if (tag != 1 && tag != 0xfffe) || bits != 16 || channels == 0 {
+                    return Err(invalid("only 16-bit PCM is supported"));
+                }
+                format = Some((channels, rate));
+            }
+            b"data" => {
+                let (channels, rate) = format.ok_or_else(|| invalid("data before fmt chunk"))?;
+                let samples = body
+                    .chunks_exact(2 * channels)
+                    .map(|frame| {
+                        let sum: i32 = frame
+                            .chunks_exact(2)
+                            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]) as i32)
+                            .sum();
+                        (sum / channels as i32) as i16
+                    })
+                    .collect();
+                return Ok((samples, rate));
+            }
+            _ => {}
+        }
+        // Chunks are word aligned
+        let next = 8 + size + (size & 1);
+        rest = &rest[next.min(rest.len())..];
+    }
+    Err(invalid("no data chunk"))
+}
+
+/// Reads a WAV file from a `file://` URI or a plain path.
+pub fn read_uri(uri: &str) -> std::io::Result<(Vec<i16>, usize)> {
+    let uri = uri.trim().trim_start_matches('<').trim_end_matches('>');
+    if let Some(path) = uri.strip_prefix("file://") {
+        return read(Path::new(path));
+    }
+    if uri.contains("://") {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("unsupported URI scheme in {:?}", uri),
+        ));
+    }
+    read(Path::new(uri))
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("wav-{}-{}.wav", std::process::id(), name))
+    }
+
+    #[test]
+    fn writes_pcm_header_and_samples() {
+        let path = temp_path("write");
+        let size = write(&path, &[1, -2, 0x1234], 8000).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(size, 50);
+        assert_eq!(data.len(), 50);
+        assert_eq!(&data[..4], b"RIFF");
+        assert_eq!(&data[4..8], &42u32.to_le_bytes());
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(&data[24..28], &8000u32.to_le_bytes());
+        assert_eq!(&data[28..32], &16000u32.to_le_bytes());
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(&data[40..44], &6u32.to_le_bytes());
+        assert_eq!(&data[44..], &[1, 0, 0xfe, 0xff, 0x34, 0x12]);
+    }
+
+    fn riff(chunks: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+        for (id, chunk) in chunks {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            body.extend_from_slice(chunk);
+            if chunk.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        data.extend(body);
+        data
+    }
+
+    fn fmt(tag: u16, channels: u16, rate: u32, bits: u16) -> Vec<u8> {
+        let block = channels * bits / 8;
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(&tag.to_le_bytes());
+        chunk.extend_from_slice(&channels.to_le_bytes());
+        chunk.extend_from_slice(&rate.to_le_bytes());
+        chunk.extend_from_slice(&(rate * block as u32).to_le_bytes());
+        chunk.extend_from_slice(&block.to_le_bytes());
+        chunk.extend_from_slice(&bits.to_le_bytes());
+        chunk
+    }
+
+    fn pcm(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    fn read_bytes(name: &str, data: &[u8]) -> std::io::Result<(Vec<i16>, usize)> {
+        let path = temp_path(name);
+        std::fs::write(&path, data).unwrap();
+        let read = read(&path);
+        std::fs::remove_file(&path).ok();
+        read
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let path = temp_path("round-trip");
+        let samples: Vec<i16> = (0..500).map(|n| (n * 131 % 65536 - 32768) as i16).collect();
+        write(&path, &samples, 16000).unwrap();
+        let read = read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(read, (samples, 16000));
+    }
+
+    #[test]
+    fn encodes_like_a_written_file() {
+        let path = temp_path("encode");
+        let samples = [3, -3, 300, i16::MIN, 0];
+        write(&path, &samples, 16000).unwrap();
+        let written = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(encode(&samples, 16000).unwrap(), written);
+        assert_eq!(encode(&[], 8000).unwrap().len(), HEADER_SIZE);
+    }
+
+    #[test]
+    fn mixes_channels_down_to_mono() {
+        let data = riff(&[
+            (b"fmt ", fmt(1, 2, 22050, 16)),
+            (b"data", pcm(&[100, 300, -100, -200, i16::MAX, i16::MAX])),
+        ]);
+        assert_eq!(
+            read_bytes("stereo", &data).unwrap(),
+            (vec![200, -150, i16::MAX], 22050)
+        );
+    }
+
+    #[test]
+    fn skips_unknown_and_odd_sized_chunks() {
+        let mut extensible = fmt(0xfffe, 1, 8000, 16);
+        extensible.extend_from_slice(&[22, 0]);
+        extensible.extend_from_slice(&[0; 22]);
+        let data = riff(&[
+            (b"fmt ", extensible),
+            (b"LIST", b"odd".to_vec()),
+            (b"data", pcm(&[7, 8, 9])),
+        ]);
+        assert_eq!(read_bytes("chunks", &data).unwrap(), (vec![7, 8, 9], 8000));
+    }
+
+    #[test]
+    fn rejects_unsupported_files() {
+        let invalid = |name: &str, data: &[u8]| {
+            let error = read_bytes(name, data).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData, "{}", name);
+        };
+        invalid("text", b"not a wave file at all");
+        invalid(
+            "8-bit",
+            &riff(&[(b"fmt ", fmt(1, 1, 8000, 8)), (b"data", vec![0; 4])]),
+        );
+        invalid(
+            "a-law",
+            &riff(&[(b"fmt ", fmt(6, 1, 8000, 16)), (b"data", vec![0; 4])]),
+        );
+        invalid("no-fmt", &riff(&[(b"data", pcm(&[1]))]));
+        invalid("no-data", &riff(&[(b"fmt ", fmt(1, 1, 8000, 16))]));
+    }
+
+    #[test]
+    fn reads_file_uris_and_paths() {
+        let path = temp_path("uri");
+        write(&path, &[5, 6], 8000).unwrap();
+        let path_str = path.to_str().unwrap();
+        assert_eq!(read_uri(path_str).unwrap(), (vec![5, 6], 8000));
+        let uri = format!(" <file://{}> ", path_str);
+        assert_eq!(read_uri(&uri).unwrap(), (vec![5, 6], 8000));
+        std::fs::remove_file(&path).ok();
+        let error = read_uri("http://example.com/a.wav").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+        let error = read_uri(&uri).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+}
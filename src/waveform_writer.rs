@@ -0,0 +1,40 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{utterance::Utterance, wav};
+
+/// Saves utterances for `Save-Waveform` on a thread of its own, away from the media thread.
+/// The thread ends when the writer is dropped, after the files queued so far.
+pub struct WaveformWriter {
+    files: Sender<(PathBuf, Arc<Utterance>)>,
+}
+
+impl WaveformWriter {
+    pub fn spawn() -> std::io::Result<Self> {
+        let (files, receiver) = mpsc::channel::<(PathBuf, Arc<Utterance>)>();
+        thread::Builder::new()
+            .name("waveform-writer".to_owned())
+            .spawn(move || {
+                for (path, utterance) in receiver {
+                    match wav::write(&path, &utterance.samples, utterance.sample_rate) {
+                        Ok(_) => log::info!("Saved waveform {:?}", path),
+                        Err(e) => log::error!("Unable to save waveform {:?}: {}", path, e),
+                    }
+                }
+            })?;
+        Ok(Self { files })
+    }
+
+    /// Queues the utterance for writing, returns the size the file will have in bytes.
+    pub fn save(&self, path: PathBuf, utterance: Arc<Utterance>) -> usize {
+        let size = wav::file_size(utterance.samples.len());
+        self.files.send((path, utterance)).ok();
+        size
+    }
+}